@@ -1,10 +1,12 @@
 use clap::Parser;
+use k8s_openapi::api::core::v1::LocalObjectReference;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
-use kube::{Api, Client};
+use kube::{Api, Client, CustomResourceExt};
 use kube::api::PostParams;
 
 use example_citus_operator::crd::{CitusCluster, CitusClusterSpec};
-use example_citus_operator::storage;
+use example_citus_operator::{credentials, storage};
 
 #[derive(Clone, Debug, Parser)]
 struct Opts {
@@ -19,6 +21,8 @@ struct Opts {
 enum Subcommand {
     Create(CreateOpts),
     Delete(DeleteOpts),
+    /// Print the CustomResourceDefinition for CitusCluster
+    Crdgen,
 }
 
 #[derive(Clone, Debug, Parser)]
@@ -33,6 +37,26 @@ struct CreateOpts {
     /// Storage volume size for each worker, in GB
     #[clap(long, default_value = "1")]
     worker_storage: usize,
+
+    /// Storage volume size for the coordinator, e.g. 10Gi; defaults to the worker storage size
+    #[clap(long)]
+    coordinator_storage: Option<String>,
+
+    /// Citus image for the coordinator and workers
+    #[clap(long, default_value = "citusdata/citus:12.1")]
+    image: String,
+
+    /// Pull policy for the Citus image
+    #[clap(long, default_value = "IfNotPresent")]
+    image_pull_policy: String,
+
+    /// Secret used to pull the Citus image; may be repeated
+    #[clap(long)]
+    image_pull_secret: Vec<String>,
+
+    /// DNS domain of the Kubernetes cluster
+    #[clap(long, default_value = "cluster.local")]
+    cluster_domain: String,
 }
 
 #[derive(Clone, Debug, Parser)]
@@ -40,7 +64,7 @@ struct DeleteOpts {
     /// Name of the cluster
     name: String,
 
-    /// Delete associated persistent storage
+    /// Delete associated persistent storage and the generated superuser password
     #[clap(long)]
     purge: bool,
 }
@@ -49,11 +73,14 @@ struct DeleteOpts {
 async fn main() {
     let opts: Opts = Opts::parse();
 
-    let client = Client::try_default().await.expect("client config");
-    let crd_api: Api<CitusCluster> = Api::namespaced(client.clone(), &opts.namespace);
-
     match opts.command {
+        Subcommand::Crdgen => {
+            let crd = serde_yaml::to_string(&CitusCluster::crd()).expect("crd yaml");
+            print!("---\n{crd}");
+        }
         Subcommand::Create(c) => {
+            let client = Client::try_default().await.expect("client config");
+            let crd_api: Api<CitusCluster> = Api::namespaced(client, &opts.namespace);
             crd_api
                 .create(
                     &PostParams::default(),
@@ -65,22 +92,53 @@ async fn main() {
                         spec: CitusClusterSpec {
                             workers: c.workers as i32,
                             worker_storage: c.worker_storage,
+                            coordinator_storage: c.coordinator_storage.map(Quantity),
+                            storage: None,
+                            image: c.image,
+                            image_pull_policy: c.image_pull_policy,
+                            image_pull_secrets: (!c.image_pull_secret.is_empty()).then(|| {
+                                c.image_pull_secret
+                                    .into_iter()
+                                    .map(|name| LocalObjectReference { name: Some(name) })
+                                    .collect()
+                            }),
+                            cluster_domain: c.cluster_domain,
+                            credentials: None,
+                            major_upgrade: None,
+                            postgresql: None,
+                            coordinator: None,
+                            worker: None,
                         },
+                        status: None,
                     },
                 )
                 .await
                 .expect("create");
         }
         Subcommand::Delete(c) => {
+            let client = Client::try_default().await.expect("client config");
+            let crd_api: Api<CitusCluster> = Api::namespaced(client.clone(), &opts.namespace);
             crd_api
                 .delete(&c.name, &Default::default())
                 .await
                 .expect("delete");
 
             if c.purge {
-                storage::delete_storage(client.clone(), &c.name, &opts.namespace)
+                let deleted = storage::delete_storage(client.clone(), &c.name, &opts.namespace)
                     .await
                     .expect("purge");
+                if deleted.is_empty() {
+                    println!("No volumes found for {}", c.name);
+                }
+                for claim in deleted {
+                    println!("Deleted persistentvolumeclaim/{claim}");
+                }
+                // The password the volumes were initialised with goes with them
+                if let Some(failure) =
+                    credentials::delete(client.clone(), &c.name, &opts.namespace).await
+                {
+                    eprintln!("Could not delete the credentials: {failure}");
+                }
             }
         }
     }
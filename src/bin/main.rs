@@ -2,6 +2,9 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use futures::StreamExt;
+use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::{ConfigMap, Service};
 use kube::{
     api::{Api, ResourceExt},
     Client,
@@ -11,7 +14,7 @@ use kube::runtime::Controller;
 use kube::runtime::controller::Action;
 use kube::runtime::watcher::Config;
 
-use example_citus_operator::cluster;
+use example_citus_operator::{cluster, status};
 use example_citus_operator::crd::CitusCluster;
 
 // use tracing::*;
@@ -24,6 +27,11 @@ async fn main() {
     let context: Arc<ContextData> = Arc::new(ContextData::new(client.clone()));
 
     Controller::new(crd_api.clone(), Config::default())
+        .owns(Api::<Deployment>::all(client.clone()), Config::default())
+        .owns(Api::<StatefulSet>::all(client.clone()), Config::default())
+        .owns(Api::<Service>::all(client.clone()), Config::default())
+        .owns(Api::<Job>::all(client.clone()), Config::default())
+        .owns(Api::<ConfigMap>::all(client.clone()), Config::default())
         .run(reconcile, on_error, context)
         .for_each(|reconciliation_result| async move {
             match reconciliation_result {
@@ -49,9 +57,8 @@ impl ContextData {
 }
 
 enum ClusterAction {
-    Create,
+    Reconcile,
     Delete,
-    NoOp,
 }
 
 async fn reconcile(cc: Arc<CitusCluster>, context: Arc<ContextData>) -> Result<Action, Error> {
@@ -65,40 +72,60 @@ async fn reconcile(cc: Arc<CitusCluster>, context: Arc<ContextData>) -> Result<A
         Some(namespace) => namespace,
     };
     let name = cc.name_any();
+    let oref = match cc.controller_owner_ref(&()) {
+        None => {
+            return Err(Error::UserInputError(
+                "Expected persisted resource.".to_owned(),
+            ));
+        }
+        Some(oref) => oref,
+    };
     match determine_action(&cc) {
-        ClusterAction::Create => {
-            cluster::add_finalizer(client.clone(), &name, &namespace).await?;
-            cluster::deploy(
-                client,
+        ClusterAction::Reconcile => {
+            if !cluster::has_finalizer(&cc) {
+                cluster::add_finalizer(client.clone(), &cc).await?;
+            }
+            let deployment = cluster::reconcile(
+                client.clone(),
                 &name,
-                cc.spec.workers,
-                cc.spec.worker_storage,
+                &cc.spec,
+                cc.status.as_ref(),
+                &oref,
                 &namespace,
             )
             .await?;
-            Ok(Action::requeue(Duration::from_secs(10)))
+            let status = status::observe(&cc, &deployment);
+            status::patch(client, &name, &status, &namespace).await?;
+            if status::settled(&status) {
+                Ok(Action::await_change())
+            } else {
+                Ok(Action::requeue(Duration::from_secs(30)))
+            }
         }
         ClusterAction::Delete => {
-            cluster::delete(client.clone(), &name, &namespace).await?;
-            cluster::delete_finalizer(client, &name, &namespace).await?;
+            let pending = cluster::delete(client.clone(), &name, &namespace).await;
+            let blocked = !pending.is_empty();
+            status::patch(
+                client.clone(),
+                &name,
+                &status::deleting(&cc, pending),
+                &namespace,
+            )
+            .await?;
+            if blocked {
+                return Ok(Action::requeue(Duration::from_secs(10)));
+            }
+            cluster::delete_finalizer(client, &cc).await?;
             Ok(Action::await_change())
         }
-        ClusterAction::NoOp => Ok(Action::requeue(Duration::from_secs(10))),
     }
 }
 
 fn determine_action(cc: &CitusCluster) -> ClusterAction {
     if cc.meta().deletion_timestamp.is_some() {
         ClusterAction::Delete
-    } else if cc
-        .meta()
-        .finalizers
-        .as_ref()
-        .map_or(true, |finalizers| finalizers.is_empty())
-    {
-        ClusterAction::Create
     } else {
-        ClusterAction::NoOp
+        ClusterAction::Reconcile
     }
 }
 
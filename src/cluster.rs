@@ -1,65 +1,454 @@
-use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
-use kube::{Api, Client, Error};
-use kube::api::{Patch, PatchParams};
+use std::fmt::Debug;
+use std::ops::Range;
+
+use k8s_openapi::api::apps::v1::StatefulSet;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+use kube::{Api, Client, Error, Resource, ResourceExt};
+use kube::api::{DeleteParams, Patch, PatchParams};
+use serde::de::DeserializeOwned;
 use serde_json::{json, Value};
 
-use crate::{jobs, master, workers};
-use crate::crd::CitusCluster;
+use crate::{
+    credentials, jobs, master, membership, pg_upgrade, postgresql, resize, storage, upgrade,
+    workers,
+};
+use crate::crd::{
+    CitusCluster, CitusClusterSpec, CitusClusterStatus, MajorUpgradeStatus, UpgradeStatus,
+    VolumeResize,
+};
+
+const FINALIZER: &str = "citusclusters.jw3.xyz/finalizer";
 
-pub type CitusDeployment = (Deployment, StatefulSet);
+pub struct CitusDeployment {
+    pub master: StatefulSet,
+    pub workers: StatefulSet,
+    /// Why convergence is stuck, if a step failed in a way retrying will not fix
+    pub degraded: Option<String>,
+    /// Progress of a rolling Citus upgrade, while one is under way
+    pub upgrade: Option<UpgradeStatus>,
+    /// Progress of a major upgrade, while one is under way
+    pub major_upgrade: Option<MajorUpgradeStatus>,
+    /// Whether the coordinator confirmed every registered worker has its metadata synced
+    pub metadata_synced: bool,
+    /// StatefulSets whose volumes are being grown
+    pub volume_resizes: Vec<VolumeResize>,
+}
 
-pub async fn deploy(
+/// Converge the children of a cluster toward the state described by its spec
+///
+/// Every child is server-side applied on each pass, so re-running any step is safe. The workers
+/// StatefulSet is scaled to match `spec.workers`, and workers are registered with the coordinator
+/// once the coordinator and their pods are ready; only ordinals that have not been registered
+/// before are added.
+/// A major upgrade or volume resize under way is resumed from the previous `status`, and a
+/// major upgrade takes over every pass until it finishes.
+pub async fn reconcile(
     client: Client,
     name: &str,
-    num_workers: i32,
-    worker_storage: usize,
+    spec: &CitusClusterSpec,
+    status: Option<&CitusClusterStatus>,
+    oref: &OwnerReference,
     namespace: &str,
 ) -> Result<CitusDeployment, Error> {
-    let master = master::deploy(client.clone(), name, namespace).await?;
-    let workers =
-        workers::deploy(client.clone(), name, num_workers, worker_storage, namespace).await?;
-    jobs::register_workers(client.clone(), name, num_workers, namespace).await?;
+    let mut degraded = None;
+
+    let live_master = master::get(client.clone(), name, namespace).await?;
+    let live = workers::get(client.clone(), name, namespace).await?;
+    let migrating = master::legacy(client.clone(), name, namespace)
+        .await?
+        .is_some();
+    // Clusters from before registration was tracked were initialised with the legacy password
+    let legacy = migrating || live.as_ref().is_some_and(|ss| !workers::tracked(ss));
+    credentials::ensure(client.clone(), name, spec, legacy, oref, namespace).await?;
+    let major_upgrade = status.and_then(|s| s.major_upgrade.as_ref());
+    if let Some(progress) =
+        pg_upgrade::pending(spec, major_upgrade, live_master.as_ref(), live.as_ref())
+    {
+        return pg_upgrade::reconcile(client, name, spec, progress, oref, namespace).await;
+    }
+
+    let mut resizes = status.map(|s| s.volume_resizes.clone()).unwrap_or_default();
+    let desired_master_claim =
+        storage::volume_claim_template(name, storage::coordinator_size(spec), spec);
+    let desired_worker_claim =
+        storage::volume_claim_template(name, storage::worker_size(spec), spec);
+    let master_resize = resize::reconcile(
+        client.clone(),
+        name,
+        name,
+        live_master.as_ref(),
+        &desired_master_claim,
+        &resizes,
+        namespace,
+    )
+    .await?;
+    // The workers' progress is recorded alongside what the coordinator's has become
+    resizes.retain(|r| r.stateful_set != name);
+    resizes.extend(master_resize.progress.clone());
+    let worker_resize = resize::reconcile(
+        client.clone(),
+        name,
+        &workers::qname(name),
+        live.as_ref(),
+        &desired_worker_claim,
+        &resizes,
+        namespace,
+    )
+    .await?;
+    degraded = master_resize.failure.or(degraded);
+    degraded = worker_resize.failure.or(degraded);
+    let volume_resizes: Vec<VolumeResize> = [&master_resize.progress, &worker_resize.progress]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+    // Wait for a StatefulSet being recreated to be gone before applying it again
+    if master_resize.recreating || worker_resize.recreating {
+        return Ok(CitusDeployment {
+            master: live_master.unwrap_or_default(),
+            workers: live.unwrap_or_default(),
+            degraded,
+            upgrade: None,
+            major_upgrade: None,
+            metadata_synced: false,
+            volume_resizes,
+        });
+    }
+
+    let config = postgresql::configure(client.clone(), name, spec, oref, namespace).await?;
+    degraded = postgresql::invalid(spec).or(degraded);
+
+    let (master_claim, reason) =
+        storage::claim_template(live_master.as_ref(), desired_master_claim, "Coordinator");
+    degraded = reason.or(degraded);
+    let (master_wal, reason) = storage::wal_template(
+        name,
+        live_master.as_ref(),
+        storage::wal_claim_template(name, spec),
+        "Coordinator",
+    );
+    degraded = reason.or(degraded);
+    let master_claims = std::iter::once(master_claim).chain(master_wal).collect();
+    if live_master
+        .as_ref()
+        .is_some_and(|ss| upgrade::image(ss) != Some(spec.image.as_str()))
+    {
+        upgrade::begin(client.clone(), name, 1, namespace).await?;
+    }
+    let mut master = master::deploy(
+        client.clone(),
+        name,
+        1,
+        master_claims,
+        spec,
+        oref,
+        namespace,
+    )
+    .await?;
+    if let (None, Some(progress)) = (&live_master, &master_resize.progress) {
+        master = resize::restore(client.clone(), progress, namespace).await?;
+    }
+    if migrating && master::ready(&master) {
+        degraded = migrate_coordinator(client.clone(), name, spec, oref, namespace)
+            .await?
+            .or(degraded);
+    }
+
+    if let Some(ss) = live
+        .as_ref()
+        .filter(|ss| upgrade::image(ss) != Some(spec.image.as_str()))
+    {
+        let partition = (workers::replicas(ss) - 1).max(0);
+        upgrade::begin(client.clone(), &workers::qname(name), partition, namespace).await?;
+    }
+    let (worker_claim, reason) =
+        storage::claim_template(live.as_ref(), desired_worker_claim, "Worker");
+    degraded = reason.or(degraded);
+    let (worker_wal, reason) = storage::wal_template(
+        name,
+        live.as_ref(),
+        storage::wal_claim_template(name, spec),
+        "Worker",
+    );
+    degraded = reason.or(degraded);
+    let worker_claims = std::iter::once(worker_claim).chain(worker_wal).collect();
+    let replicas = match &live {
+        None => worker_resize
+            .progress
+            .as_ref()
+            .map_or(spec.workers, |progress| progress.replicas),
+        Some(ss) => {
+            if workers::replicas(ss) > spec.workers {
+                let (replicas, failure) =
+                    drain(client.clone(), name, ss, spec.workers, spec, namespace).await?;
+                degraded = failure.or(degraded);
+                replicas
+            } else {
+                spec.workers
+            }
+        }
+    };
+    // Applying marks the set as tracked, so an older set must have its count recorded first
+    if let Some(ss) = live.as_ref().filter(|ss| !workers::tracked(ss)) {
+        workers::mark_registered(client.clone(), name, workers::registered(ss), namespace).await?;
+    }
+    let mut workers = workers::deploy(
+        client.clone(),
+        name,
+        replicas,
+        worker_claims,
+        spec,
+        oref,
+        namespace,
+    )
+    .await?;
+    if let (None, Some(progress)) = (&live, &worker_resize.progress) {
+        workers = resize::restore(client.clone(), progress, namespace).await?;
+    }
+    // Registration waits for the coordinator as well as the workers, so the Job never races
+    // Postgres starting up
+    if master::ready(&master) {
+        let failure;
+        (workers, failure) =
+            register_ready_workers(client.clone(), name, workers, spec, namespace).await?;
+        degraded = failure.or(degraded);
+    }
+
+    let upgrade = upgrade::reconcile(
+        client.clone(),
+        name,
+        &master,
+        &workers,
+        spec,
+        oref,
+        namespace,
+    )
+    .await?;
 
-    master::expose(client.clone(), name, namespace).await?;
-    workers::expose(client.clone(), name, namespace).await?;
+    let mut metadata_synced = false;
+    if upgrade.is_none()
+        && master::ready(&master)
+        && workers::ready_replicas(&workers) >= workers::replicas(&workers)
+    {
+        let membership =
+            membership::reconcile(client.clone(), name, &workers, spec, namespace).await?;
+        metadata_synced = membership.metadata_synced;
+        degraded = membership.failure.or(degraded);
+        let hosts = std::iter::once(master::host(name, spec, namespace))
+            .chain(
+                (0..workers::registered(&workers)).map(|i| workers::host(name, i, spec, namespace)),
+            )
+            .collect();
+        degraded = postgresql::reload(client.clone(), name, &config, hosts, spec, oref, namespace)
+            .await?
+            .or(degraded);
+    }
 
-    Ok((master, workers))
+    master::expose(client.clone(), name, migrating, oref, namespace).await?;
+    workers::expose(client.clone(), name, oref, namespace).await?;
+
+    Ok(CitusDeployment {
+        master,
+        workers,
+        degraded,
+        upgrade,
+        major_upgrade: None,
+        metadata_synced,
+        volume_resizes,
+    })
 }
 
-pub async fn delete(client: Client, name: &str, namespace: &str) -> Result<(), Error> {
-    master::delete(client.clone(), name, namespace).await?;
-    workers::delete(client.clone(), name, namespace).await?;
+/// Move a coordinator that still runs as a Deployment onto the coordinator StatefulSet
+///
+/// The database is copied from the old coordinator pod to the new one, and the Deployment is
+/// only deleted once the copy has succeeded. Returns the reason if the copy failed.
+async fn migrate_coordinator(
+    client: Client,
+    name: &str,
+    spec: &CitusClusterSpec,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<Option<String>, Error> {
+    let Some((from, to)) = master::migration_hosts(client.clone(), name, namespace).await? else {
+        return Ok(None);
+    };
+    let job =
+        jobs::migrate_coordinator(client.clone(), name, &from, &to, spec, oref, namespace).await?;
+    if jobs::failed(&job) {
+        return Ok(Some(
+            "Copying the coordinator onto its StatefulSet failed; the old coordinator stays \
+             read-only"
+                .to_owned(),
+        ));
+    }
+    if !jobs::succeeded(&job) {
+        return Ok(None);
+    }
 
-    Ok(())
+    master::delete_legacy(client.clone(), name, namespace).await?;
+    jobs::delete_migrate_job(client, name, namespace).await?;
+    Ok(None)
 }
 
-pub async fn add_finalizer(
+/// Drain the workers above ordinal `target` so the worker set can shrink without losing shard
+/// placements
+///
+/// The highest ordinals are drained and removed from the coordinator first. Returns the replica
+/// count the StatefulSet may be applied with: `target` once draining has succeeded and the
+/// current count until then, along with the reason if the drain failed.
+async fn drain(
     client: Client,
     name: &str,
+    workers: &StatefulSet,
+    target: i32,
+    spec: &CitusClusterSpec,
     namespace: &str,
-) -> Result<CitusCluster, Error> {
-    let api: Api<CitusCluster> = Api::namespaced(client, namespace);
-    let finalizer: Value = json!({
-        "metadata": {
-            "finalizers": ["citusclusters.jw3.xyz/finalizer"]
+) -> Result<(i32, Option<String>), Error> {
+    let current = workers::replicas(workers);
+    if let Some(ordinals) = drain_ordinals(workers::registered(workers), target) {
+        let failure = membership::drain(client.clone(), name, ordinals, spec, namespace).await?;
+        if failure.is_some() {
+            return Ok((current, failure));
         }
-    });
+        workers::mark_registered(client, name, target, namespace).await?;
+    }
 
-    let patch: Patch<&Value> = Patch::Merge(&finalizer);
-    api.patch(name, &PatchParams::default(), &patch).await
+    Ok((target, None))
 }
 
-pub async fn delete_finalizer(
+/// The registered worker ordinals to drain so that only `target` workers are left
+fn drain_ordinals(registered: i32, target: i32) -> Option<Range<i32>> {
+    (registered > target).then_some(target..registered)
+}
+
+/// Register any ready worker ordinals that the coordinator does not know about yet
+///
+/// The ordinals are only recorded as registered once the coordinator has added them. Returns
+/// the reason if it could not.
+async fn register_ready_workers(
     client: Client,
     name: &str,
+    workers: StatefulSet,
+    spec: &CitusClusterSpec,
     namespace: &str,
+) -> Result<(StatefulSet, Option<String>), Error> {
+    let replicas = workers::replicas(&workers);
+    let registered = workers::registered(&workers);
+    if registered >= replicas || workers::ready_replicas(&workers) < replicas {
+        return Ok((workers, None));
+    }
+
+    let ordinals = registered..replicas;
+    let failure = membership::register(client.clone(), name, ordinals, spec, namespace).await?;
+    if failure.is_some() {
+        return Ok((workers, failure));
+    }
+
+    let workers = workers::mark_registered(client, name, replicas, namespace).await?;
+    Ok((workers, None))
+}
+
+/// Delete every child of a cluster, returning those that could not be deleted
+///
+/// Children that are already gone count as deleted, and a failure to delete one child does not
+/// stop the others from being deleted.
+pub async fn delete(client: Client, name: &str, namespace: &str) -> Vec<String> {
+    let mut pending = master::delete(client.clone(), name, namespace).await;
+    pending.extend(workers::delete(client.clone(), name, namespace).await);
+    pending.extend(postgresql::delete(client.clone(), name, namespace).await);
+    pending
+}
+
+/// Delete a single child, treating one that no longer exists as deleted
+///
+/// Returns a description of the child and the error if it could not be deleted.
+pub(crate) async fn delete_child<K>(api: &Api<K>, name: &str) -> Option<String>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug,
+{
+    match api.delete(name, &DeleteParams::default()).await {
+        Ok(_) => None,
+        Err(Error::Api(e)) if e.code == 404 => None,
+        Err(e) => Some(format!("{}/{name}: {e}", K::kind(&()))),
+    }
+}
+
+/// Add our finalizer, leaving any other controller's in place
+///
+/// The patch only applies to the version of the cluster that was read, so a concurrent change
+/// to the finalizers fails it instead of being overwritten.
+pub async fn add_finalizer(client: Client, cc: &CitusCluster) -> Result<CitusCluster, Error> {
+    let api: Api<CitusCluster> = Api::namespaced(client, &cc.namespace().unwrap_or_default());
+    let add = match &cc.meta().finalizers {
+        Some(_) => json!({ "op": "add", "path": "/metadata/finalizers/-", "value": FINALIZER }),
+        None => json!({ "op": "add", "path": "/metadata/finalizers", "value": [FINALIZER] }),
+    };
+    let operations = json!([precondition(cc), add]);
+    finalizer_patch(&api, cc, operations).await
+}
+
+/// Remove our finalizer, leaving any other controller's in place
+pub async fn delete_finalizer(client: Client, cc: &CitusCluster) -> Result<CitusCluster, Error> {
+    let api: Api<CitusCluster> = Api::namespaced(client, &cc.namespace().unwrap_or_default());
+    let Some(i) = cc
+        .meta()
+        .finalizers
+        .iter()
+        .flatten()
+        .position(|f| f == FINALIZER)
+    else {
+        return Ok(cc.clone());
+    };
+    let path = format!("/metadata/finalizers/{i}");
+    let operations = json!([
+        precondition(cc),
+        { "op": "test", "path": path, "value": FINALIZER },
+        { "op": "remove", "path": path },
+    ]);
+    finalizer_patch(&api, cc, operations).await
+}
+
+pub fn has_finalizer(cc: &CitusCluster) -> bool {
+    cc.meta()
+        .finalizers
+        .as_ref()
+        .is_some_and(|finalizers| finalizers.iter().any(|f| f == FINALIZER))
+}
+
+/// A JSON patch operation that fails unless the cluster is still at the version that was read
+fn precondition(cc: &CitusCluster) -> Value {
+    json!({
+        "op": "test",
+        "path": "/metadata/resourceVersion",
+        "value": cc.resource_version(),
+    })
+}
+
+async fn finalizer_patch(
+    api: &Api<CitusCluster>,
+    cc: &CitusCluster,
+    operations: Value,
 ) -> Result<CitusCluster, Error> {
-    let api: Api<CitusCluster> = Api::namespaced(client, namespace);
-    let finalizer: Value = json!({
-        "metadata": {
-            "finalizers": null
-        }
-    });
-    let patch: Patch<&Value> = Patch::Merge(&finalizer);
-    api.patch(name, &PatchParams::default(), &patch).await
+    let operations: json_patch::Patch =
+        serde_json::from_value(operations).map_err(Error::SerdeError)?;
+    let patch: Patch<()> = Patch::Json(operations);
+    api.patch(&cc.name_any(), &PatchParams::default(), &patch)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drains_the_highest_registered_ordinals() {
+        assert_eq!(drain_ordinals(5, 3), Some(3..5));
+        assert_eq!(drain_ordinals(1, 0), Some(0..1));
+    }
+
+    #[test]
+    fn nothing_to_drain_when_growing_or_unchanged() {
+        assert_eq!(drain_ordinals(3, 3), None);
+        assert_eq!(drain_ordinals(2, 4), None);
+    }
 }
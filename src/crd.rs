@@ -1,3 +1,10 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::core::v1::{
+    Affinity, LocalObjectReference, ResourceRequirements, Toleration, TopologySpreadConstraint,
+};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, LabelSelector};
 use kube::CustomResource;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -9,9 +16,238 @@ use serde::{Deserialize, Serialize};
     kind = "CitusCluster",
     plural = "citusclusters",
     derive = "PartialEq",
+    status = "CitusClusterStatus",
+    shortname = "cc",
+    printcolumn = r#"{"name":"Workers","type":"integer","jsonPath":".spec.workers"}"#,
+    printcolumn = r#"{"name":"Ready","type":"integer","jsonPath":".status.readyWorkers"}"#,
+    printcolumn = r#"{"name":"Registered","type":"integer","jsonPath":".status.registeredWorkers"}"#,
+    printcolumn = r#"{"name":"Phase","type":"string","jsonPath":".status.phase"}"#,
+    printcolumn = r#"{"name":"Age","type":"date","jsonPath":".metadata.creationTimestamp"}"#,
     namespaced
 )]
+#[serde(rename_all = "camelCase")]
 pub struct CitusClusterSpec {
     pub workers: i32,
+    /// Size of each volume in Gi, unless `storage.size` is set
+    #[serde(rename = "worker_storage")]
     pub worker_storage: usize,
+    /// Size of each coordinator volume; defaults to the size of the worker volumes
+    pub coordinator_storage: Option<Quantity>,
+    pub storage: Option<Storage>,
+    /// Citus image run by the coordinator, the workers and every Job
+    #[serde(default = "default_image")]
+    pub image: String,
+    #[serde(default = "default_image_pull_policy")]
+    pub image_pull_policy: String,
+    pub image_pull_secrets: Option<Vec<LocalObjectReference>>,
+    /// DNS domain of the Kubernetes cluster, used to build the fully qualified node names
+    #[serde(default = "default_cluster_domain")]
+    pub cluster_domain: String,
+    /// Existing Secret holding the superuser password; one is generated when omitted
+    pub credentials: Option<Credentials>,
+    /// Move onto `image` with pg_upgrade instead of a rolling restart; required when `image`
+    /// ships a new PostgreSQL major version
+    pub major_upgrade: Option<MajorUpgrade>,
+    pub postgresql: Option<Postgresql>,
+    /// Settings for the coordinator pod
+    pub coordinator: Option<Role>,
+    /// Settings for the worker pods; `workers` holds how many there are
+    pub worker: Option<Role>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Postgresql {
+    /// Configuration parameters for every node; those that need a restart roll the pods
+    #[serde(default)]
+    pub parameters: BTreeMap<String, String>,
+}
+
+/// Settings that apply to every pod of one role, the coordinator or the workers
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Role {
+    /// Resources of the Postgres container; its memory also sizes shared_buffers and work_mem
+    pub resources: Option<ResourceRequirements>,
+    pub node_selector: Option<BTreeMap<String, String>>,
+    pub tolerations: Option<Vec<Toleration>>,
+    /// Defaults to spreading the workers over nodes and zones; the coordinator has none
+    pub affinity: Option<Affinity>,
+    pub topology_spread_constraints: Option<Vec<TopologySpreadConstraint>>,
+}
+
+/// Volumes claimed by the coordinator and every worker
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Storage {
+    /// Size of each volume; defaults to `worker_storage` Gi
+    pub size: Option<Quantity>,
+    /// Uses the default StorageClass when omitted
+    pub storage_class_name: Option<String>,
+    /// Defaults to ReadWriteOnce
+    pub access_modes: Option<Vec<String>>,
+    /// Defaults to Filesystem
+    pub volume_mode: Option<String>,
+    /// Restricts the volumes that can be bound to the claims
+    pub selector: Option<LabelSelector>,
+    pub annotations: Option<BTreeMap<String, String>>,
+    /// What happens to the volumes when the cluster is deleted; they are kept by default
+    /// along with the generated superuser password they were initialised with
+    #[serde(default)]
+    pub retention_policy: RetentionPolicy,
+    /// Keeps the write-ahead log of every node on a volume of its own
+    pub wal: Option<WalStorage>,
+}
+
+/// A volume for the write-ahead log, claimed next to the data volume of each node
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WalStorage {
+    pub size: Quantity,
+    /// Uses the default StorageClass when omitted
+    pub storage_class_name: Option<String>,
+    /// Defaults to ReadWriteOnce
+    pub access_modes: Option<Vec<String>>,
+    pub annotations: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy, JsonSchema)]
+pub enum RetentionPolicy {
+    #[default]
+    Retain,
+    Delete,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Credentials {
+    pub secret_name: String,
+    #[serde(default = "default_password_key")]
+    pub key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MajorUpgrade {
+    /// PostgreSQL major version shipped with `image`
+    pub postgres_version: u32,
+    /// Image holding the PostgreSQL and Citus binaries of both the old and the new version,
+    /// which pg_upgrade is run from
+    pub upgrade_image: String,
+}
+
+fn default_image() -> String {
+    "citusdata/citus:12.1".to_owned()
+}
+
+fn default_image_pull_policy() -> String {
+    "IfNotPresent".to_owned()
+}
+
+fn default_cluster_domain() -> String {
+    "cluster.local".to_owned()
+}
+
+fn default_password_key() -> String {
+    crate::credentials::PASSWORD_KEY.to_owned()
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CitusClusterStatus {
+    #[serde(default)]
+    pub phase: ClusterPhase,
+    #[serde(default)]
+    pub ready_workers: i32,
+    #[serde(default)]
+    pub registered_workers: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coordinator_endpoint: Option<String>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    /// Children that could not be deleted while the cluster is being torn down
+    #[serde(default)]
+    pub pending_deletion: Vec<String>,
+    /// Progress of a rolling Citus upgrade, while one is under way
+    #[serde(default)]
+    pub upgrade: Option<UpgradeStatus>,
+    /// Progress of a major upgrade, which is resumed from here on every pass until it finishes
+    #[serde(default)]
+    pub major_upgrade: Option<MajorUpgradeStatus>,
+    /// StatefulSets whose volumes are being grown
+    #[serde(default)]
+    pub volume_resizes: Vec<VolumeResize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VolumeResize {
+    pub stateful_set: String,
+    /// Size the volumes are growing to
+    pub size: Quantity,
+    /// Replicas of the StatefulSet before it was recreated with the new size
+    pub replicas: i32,
+    /// Annotations of the StatefulSet before it was recreated, put back once it is applied again
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    /// Claims that do not have the new capacity yet
+    #[serde(default)]
+    pub pending_claims: Vec<String>,
+    /// Resize conditions reported by the pending claims, such as FileSystemResizePending
+    #[serde(default)]
+    pub claim_conditions: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UpgradeStatus {
+    /// Image the cluster is being upgraded to
+    pub image: String,
+    /// Pod of the node currently being upgraded
+    pub node: String,
+    /// Why upgrading the current node failed, if it did
+    pub failure: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MajorUpgradeStatus {
+    /// Image the cluster ran before the upgrade
+    pub from_image: String,
+    /// Image the cluster is being upgraded to
+    pub image: String,
+    pub step: MajorUpgradeStep,
+    /// Number of workers when the upgrade started, which are all upgraded and restarted
+    pub workers: i32,
+    /// Why the current step failed, if it did
+    pub failure: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, JsonSchema)]
+pub enum MajorUpgradeStep {
+    /// Making every node read-only and running citus_prepare_pg_upgrade()
+    StoppingWrites,
+    /// Scaling the coordinator and the workers down to zero
+    Stopping,
+    /// Running pg_upgrade against every data volume
+    Upgrading,
+    /// Starting the coordinator and the workers on the new image
+    Restarting,
+    /// Running citus_finish_pg_upgrade() on every node
+    Finishing,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy, JsonSchema)]
+pub enum ClusterPhase {
+    #[default]
+    Pending,
+    Bootstrapping,
+    Ready,
+    Scaling,
+    Resizing,
+    Upgrading,
+    Degraded,
+    Deleting,
 }
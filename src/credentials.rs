@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::core::v1::{EnvVar, EnvVarSource, Secret, SecretKeySelector};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference};
+use kube::api::{Patch, PatchParams, PostParams};
+use kube::{Api, Client, Error};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_json::{json, Value};
+
+use crate::crd::{CitusClusterSpec, RetentionPolicy};
+use crate::{cluster, storage};
+
+pub const PASSWORD_KEY: &str = "password";
+
+/// The superuser password every cluster was initialised with before passwords were generated
+const LEGACY_PASSWORD: &str = "yourpassword";
+
+/// Generate the superuser password Secret unless the spec names an existing one
+///
+/// A generated password is only ever written once; later passes reuse the existing Secret so
+/// the password keeps matching the one Postgres was initialised with. A cluster whose data
+/// predates generated passwords (`legacy`) gets the password it was initialised with instead.
+///
+/// The Secret is only owned by the cluster while its volumes are deleted with it. Retained
+/// volumes keep the password they were initialised with, so the Secret has to outlive the
+/// cluster for a recreated one to use it.
+pub async fn ensure(
+    client: Client,
+    name: &str,
+    spec: &CitusClusterSpec,
+    legacy: bool,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<(), Error> {
+    if spec.credentials.is_some() {
+        return Ok(());
+    }
+
+    let secret_api: Api<Secret> = Api::namespaced(client, namespace);
+    let secret_name = qname(name);
+    let owner_references = match storage::retention(spec) {
+        RetentionPolicy::Delete => Some(vec![oref.clone()]),
+        RetentionPolicy::Retain => None,
+    };
+    match secret_api.get_opt(&secret_name).await? {
+        None => {
+            let password = if legacy {
+                LEGACY_PASSWORD.to_owned()
+            } else {
+                rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(32)
+                    .map(char::from)
+                    .collect()
+            };
+            let secret = Secret {
+                metadata: ObjectMeta {
+                    name: Some(secret_name.clone()),
+                    namespace: Some(namespace.to_owned()),
+                    owner_references,
+                    ..ObjectMeta::default()
+                },
+                type_: Some("Opaque".to_owned()),
+                string_data: Some(BTreeMap::from([(PASSWORD_KEY.to_owned(), password)])),
+                ..Secret::default()
+            };
+            secret_api.create(&PostParams::default(), &secret).await?;
+        }
+        // Follow a change of the retention policy
+        Some(secret) if secret.metadata.owner_references != owner_references => {
+            let owners = json!({ "metadata": { "ownerReferences": owner_references } });
+            let patch: Patch<&Value> = Patch::Merge(&owners);
+            secret_api
+                .patch(&secret_name, &PatchParams::default(), &patch)
+                .await?;
+        }
+        Some(_) => {}
+    }
+
+    Ok(())
+}
+
+/// Delete the generated password Secret of a cluster, for when its volumes are deleted
+///
+/// Returns a description of the Secret and the error if it could not be deleted.
+pub async fn delete(client: Client, name: &str, namespace: &str) -> Option<String> {
+    let secret_api: Api<Secret> = Api::namespaced(client, namespace);
+    cluster::delete_child(&secret_api, &qname(name)).await
+}
+
+/// Read the superuser password of a cluster, if its Secret holds one
+pub async fn password(
+    client: Client,
+    name: &str,
+    spec: &CitusClusterSpec,
+    namespace: &str,
+) -> Result<Option<String>, Error> {
+    let selector = selector(name, spec);
+    let secret_api: Api<Secret> = Api::namespaced(client, namespace);
+    let secret = secret_api
+        .get_opt(&selector.name.unwrap_or_default())
+        .await?;
+
+    Ok(secret
+        .and_then(|s| s.data)
+        .and_then(|data| data.get(&selector.key).cloned())
+        .map(|password| String::from_utf8_lossy(&password.0).into_owned()))
+}
+
+/// The Secret key holding the superuser password of a cluster
+pub fn selector(name: &str, spec: &CitusClusterSpec) -> SecretKeySelector {
+    match &spec.credentials {
+        Some(credentials) => SecretKeySelector {
+            name: Some(credentials.secret_name.clone()),
+            key: credentials.key.clone(),
+            optional: None,
+        },
+        None => SecretKeySelector {
+            name: Some(qname(name)),
+            key: PASSWORD_KEY.to_owned(),
+            optional: None,
+        },
+    }
+}
+
+/// An environment variable whose value is read from the credentials Secret
+pub fn env(var: &str, name: &str, spec: &CitusClusterSpec) -> EnvVar {
+    EnvVar {
+        name: var.to_owned(),
+        value_from: Some(EnvVarSource {
+            secret_key_ref: Some(selector(name, spec)),
+            ..EnvVarSource::default()
+        }),
+        ..EnvVar::default()
+    }
+}
+
+pub(crate) fn qname(name: &str) -> String {
+    format!("{name}-credentials")
+}
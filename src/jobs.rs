@@ -1,47 +1,599 @@
+use std::collections::BTreeMap;
+
 use k8s_openapi::api::batch::v1::{Job, JobSpec};
-use k8s_openapi::api::core::v1::{Container, EnvVar, PodSpec, PodTemplateSpec};
-use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use k8s_openapi::api::core::v1::{
+    Container, EnvVar, PersistentVolumeClaim, PersistentVolumeClaimVolumeSource, Pod,
+    PodSecurityContext, PodSpec, PodTemplateSpec, Volume, VolumeMount,
+};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference};
 use kube::{Api, Client, Error};
-use kube::api::PostParams;
+use kube::api::{DeleteParams, ListParams, LogParams, PostParams, PropagationPolicy};
+
+use crate::{credentials, master, postgresql, storage, workers};
+use crate::crd::{CitusClusterSpec, MajorUpgrade};
+
+/// How long finished Jobs are kept around for inspection; a failed Job is retried once it is gone
+const JOB_TTL_SECONDS: i32 = 3600;
+/// How much of a failed Job's log is reported in the cluster status
+const FAILURE_LOG_LINES: i64 = 10;
+
+/// Parameters applied by a reload Job
+const PARAMETERS_ANNOTATION: &str = "citusclusters.jw3.xyz/parameters";
+
+/// Fails unless the installed Citus extension is at the default version of the running image
+const VERIFY_EXTENSION_VERSION: &str = r#"DO $$
+DECLARE
+    installed text := (SELECT extversion FROM pg_extension WHERE extname = 'citus');
+    available text := (SELECT default_version FROM pg_available_extensions WHERE name = 'citus');
+BEGIN
+    IF installed IS DISTINCT FROM available THEN
+        RAISE EXCEPTION 'citus is at %, expected %', installed, available;
+    END IF;
+END $$"#;
+
+/// Copy the database of the legacy coordinator Deployment at `from` into the coordinator
+/// StatefulSet at `to`
+///
+/// The Citus metadata tables are dumped along with the extension, so the new coordinator
+/// keeps every distributed table and registered worker. Writes on the legacy coordinator are
+/// stopped and its other sessions ended before the dump, so nothing written after the dump
+/// is lost; it stays read-only from then on.
+pub async fn migrate_coordinator(
+    client: Client,
+    name: &str,
+    from: &str,
+    to: &str,
+    spec: &CitusClusterSpec,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<Job, Error> {
+    let jobs_api: Api<Job> = Api::namespaced(client.clone(), namespace);
+    let job_name = migrate_job_name(name);
+    if let Some(job) = jobs_api.get_opt(&job_name).await? {
+        return Ok(job);
+    }
+
+    let migrate_job = job(
+        name,
+        ObjectMeta {
+            name: Some(job_name),
+            owner_references: Some(vec![oref.clone()]),
+            ..ObjectMeta::default()
+        },
+        format!("{name}-migrate-coordinator"),
+        to.to_owned(),
+        vec![
+            "bash".to_owned(),
+            "-c".to_owned(),
+            format!(
+                "set -o pipefail; psql -h {from} -d postgres -v ON_ERROR_STOP=1 \
+                 -c \"ALTER SYSTEM SET default_transaction_read_only = on\" \
+                 -c \"SELECT pg_reload_conf()\" \
+                 -c \"SELECT pg_terminate_backend(pid) FROM pg_stat_activity \
+                 WHERE pid <> pg_backend_pid() AND backend_type = 'client backend'\" \
+                 && pg_dump -Fc -h {from} postgres \
+                 | pg_restore --clean --if-exists --exit-on-error -d postgres"
+            ),
+        ],
+        spec,
+    );
+    jobs_api.create(&PostParams::default(), &migrate_job).await
+}
 
-use crate::workers;
+pub async fn delete_migrate_job(client: Client, name: &str, namespace: &str) -> Result<(), Error> {
+    delete(client, &migrate_job_name(name), namespace).await
+}
 
-pub async fn register_workers(
+/// Update the Citus extension on the node at `host` to the version shipped with its image
+///
+/// The Job fails unless the installed extension ends up at the image's default version.
+/// `node` names the node in the Job name, so each node gets its own Job.
+pub async fn update_extension(
     client: Client,
     name: &str,
-    cnt: i32,
+    node: &str,
+    host: String,
+    spec: &CitusClusterSpec,
+    oref: &OwnerReference,
     namespace: &str,
 ) -> Result<Job, Error> {
-    let init_job = Job {
-        metadata: ObjectMeta {
-            generate_name: Some(format!("{name}-init")),
+    let jobs_api: Api<Job> = Api::namespaced(client.clone(), namespace);
+    let job_name = update_job_name(name, node);
+    if let Some(job) = jobs_api.get_opt(&job_name).await? {
+        return Ok(job);
+    }
+
+    let update_job = psql_job(
+        name,
+        ObjectMeta {
+            name: Some(job_name),
+            owner_references: Some(vec![oref.clone()]),
             ..ObjectMeta::default()
         },
+        format!("{name}-update-citus"),
+        host,
+        vec![
+            "ALTER EXTENSION citus UPDATE".to_owned(),
+            VERIFY_EXTENSION_VERSION.to_owned(),
+            "SELECT citus_version()".to_owned(),
+        ],
+        spec,
+    );
+    jobs_api.create(&PostParams::default(), &update_job).await
+}
+
+pub async fn delete_update_job(
+    client: Client,
+    name: &str,
+    node: &str,
+    namespace: &str,
+) -> Result<(), Error> {
+    delete(client, &update_job_name(name, node), namespace).await
+}
+
+/// Make every node in `hosts` read-only and prepare its Citus metadata for pg_upgrade
+///
+/// The prepare step runs with writes re-enabled for its own session only.
+pub async fn prepare_pg_upgrade(
+    client: Client,
+    name: &str,
+    hosts: Vec<String>,
+    spec: &CitusClusterSpec,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<Job, Error> {
+    let statements = vec![
+        "ALTER SYSTEM SET default_transaction_read_only = on".to_owned(),
+        "SELECT pg_reload_conf()".to_owned(),
+        "SET default_transaction_read_only = off".to_owned(),
+        "SELECT citus_prepare_pg_upgrade()".to_owned(),
+    ];
+    let jobs_api: Api<Job> = Api::namespaced(client.clone(), namespace);
+    let job_name = pg_upgrade_job_name(name, "prepare");
+    if let Some(job) = jobs_api.get_opt(&job_name).await? {
+        return Ok(job);
+    }
+
+    let prepare_job = psql_each_job(
+        name,
+        ObjectMeta {
+            name: Some(job_name),
+            owner_references: Some(vec![oref.clone()]),
+            ..ObjectMeta::default()
+        },
+        format!("{name}-pg-upgrade-prepare"),
+        hosts,
+        statements,
+        spec,
+    );
+    jobs_api.create(&PostParams::default(), &prepare_job).await
+}
+
+/// Restore the Citus metadata on every node in `hosts` after pg_upgrade
+pub async fn finish_pg_upgrade(
+    client: Client,
+    name: &str,
+    hosts: Vec<String>,
+    spec: &CitusClusterSpec,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<Job, Error> {
+    let statements = vec![
+        "ALTER SYSTEM RESET default_transaction_read_only".to_owned(),
+        "SELECT pg_reload_conf()".to_owned(),
+        "SELECT citus_finish_pg_upgrade()".to_owned(),
+    ];
+    let jobs_api: Api<Job> = Api::namespaced(client.clone(), namespace);
+    let job_name = pg_upgrade_job_name(name, "finish");
+    if let Some(job) = jobs_api.get_opt(&job_name).await? {
+        return Ok(job);
+    }
+
+    let finish_job = psql_each_job(
+        name,
+        ObjectMeta {
+            name: Some(job_name),
+            owner_references: Some(vec![oref.clone()]),
+            ..ObjectMeta::default()
+        },
+        format!("{name}-pg-upgrade-finish"),
+        hosts,
+        statements,
+        spec,
+    );
+    jobs_api.create(&PostParams::default(), &finish_job).await
+}
+
+pub async fn delete_pg_upgrade_job(
+    client: Client,
+    name: &str,
+    step: &str,
+    namespace: &str,
+) -> Result<(), Error> {
+    delete(client, &pg_upgrade_job_name(name, step), namespace).await
+}
+
+/// Upgrade the data directory of worker `ordinal`, or of the coordinator when it is `None`, in
+/// place with pg_upgrade
+///
+/// The old cluster is moved aside within the volume and hard-linked into a freshly initialised
+/// one, so the volume needs little free space. Each stage can be re-run after a failure, and a
+/// data directory that is already at the new version is left alone.
+pub async fn pg_upgrade(
+    client: Client,
+    name: &str,
+    ordinal: Option<i32>,
+    upgrade: &MajorUpgrade,
+    spec: &CitusClusterSpec,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<Job, Error> {
+    let jobs_api: Api<Job> = Api::namespaced(client.clone(), namespace);
+    let ss_name = match ordinal {
+        Some(_) => workers::qname(name),
+        None => name.to_owned(),
+    };
+    let claim = storage::claim_name(name, &ss_name, ordinal.unwrap_or_default());
+    let job_name = pg_upgrade_job_name(name, &upgrade_node(ordinal));
+    if let Some(job) = jobs_api.get_opt(&job_name).await? {
+        return Ok(job);
+    }
+    // The old data directory links to its WAL volume, so that has to be mounted where the node
+    // mounts it, and the new cluster keeps its log there too
+    let wal_claim = storage::claim_name(
+        &storage::wal_qname(name),
+        &ss_name,
+        ordinal.unwrap_or_default(),
+    );
+    let pvc_api: Api<PersistentVolumeClaim> = Api::namespaced(client.clone(), namespace);
+    let wal = pvc_api.get_opt(&wal_claim).await?.is_some();
+    let wal_dir = storage::WAL_PATH;
+    let (rm_wal, waldir, swap_wal) = if wal {
+        (
+            format!(" {wal_dir}.new"),
+            format!(" --waldir={wal_dir}.new"),
+            format!(
+                r#"if [ -d {wal_dir}.new ]; then
+    ln -sfn {wal_dir} .new/pg_wal
+    rm -rf {wal_dir}
+    mv {wal_dir}.new {wal_dir}
+fi
+"#
+            ),
+        )
+    } else {
+        (String::new(), String::new(), String::new())
+    };
+
+    let version = upgrade.postgres_version;
+    let data_dir = storage::data_directory(ordinal);
+    let script = format!(
+        r#"set -euo pipefail
+cd {data_dir}
+bin=/usr/lib/postgresql/{version}/bin
+if [ ! -e .upgraded ]; then
+    if [ "$(cat PG_VERSION 2>/dev/null)" = "{version}" ]; then exit 0; fi
+    mkdir -p .old
+    find . -mindepth 1 -maxdepth 1 ! -name .old ! -name .new ! -name lost+found -exec mv -t .old {{}} +
+    rm -rf .new{rm_wal}
+    "$bin/initdb" -D .new --username=postgres{waldir}
+    echo "shared_preload_libraries = 'citus'" >> .new/postgresql.conf
+    echo "listen_addresses = '*'" >> .new/postgresql.conf
+    cp .old/pg_hba.conf .new/pg_hba.conf
+    old_bin="/usr/lib/postgresql/$(cat .old/PG_VERSION)/bin"
+    (cd /tmp && "$bin/pg_upgrade" --link --username=postgres -b "$old_bin" -B "$bin" -d {data_dir}/.old -D {data_dir}/.new)
+    touch .upgraded
+fi
+{swap_wal}find .new -mindepth 1 -maxdepth 1 -exec mv -t . {{}} +
+rm -rf .old .new .upgraded"#
+    );
+    let mut upgrade_job = job(
+        name,
+        ObjectMeta {
+            name: Some(job_name),
+            owner_references: Some(vec![oref.clone()]),
+            ..ObjectMeta::default()
+        },
+        format!("{name}-pg-upgrade"),
+        master::host(name, spec, namespace),
+        vec!["bash".to_owned(), "-c".to_owned(), script],
+        spec,
+    );
+    if let Some(pod) = upgrade_job
+        .spec
+        .as_mut()
+        .and_then(|s| s.template.spec.as_mut())
+    {
+        // pg_upgrade refuses to run as root; 999 is postgres in the Debian based images. The
+        // group lets it write to the root of the WAL volume, which only the log inside belongs to
+        pod.security_context = Some(PodSecurityContext {
+            run_as_user: Some(999),
+            run_as_group: Some(999),
+            fs_group: Some(999),
+            ..PodSecurityContext::default()
+        });
+        let mut volumes = vec![Volume {
+            name: "data".to_owned(),
+            persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
+                claim_name: claim,
+                ..PersistentVolumeClaimVolumeSource::default()
+            }),
+            ..Volume::default()
+        }];
+        let mut volume_mounts = vec![VolumeMount {
+            name: "data".to_owned(),
+            mount_path: storage::DATA_DIR.to_owned(),
+            ..VolumeMount::default()
+        }];
+        if wal {
+            volumes.push(Volume {
+                name: "wal".to_owned(),
+                persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
+                    claim_name: wal_claim,
+                    ..PersistentVolumeClaimVolumeSource::default()
+                }),
+                ..Volume::default()
+            });
+            volume_mounts.push(VolumeMount {
+                name: "wal".to_owned(),
+                mount_path: storage::WAL_DIR.to_owned(),
+                ..VolumeMount::default()
+            });
+        }
+        pod.volumes = Some(volumes);
+        for container in pod.containers.iter_mut() {
+            container.image = Some(upgrade.upgrade_image.clone());
+            container.volume_mounts = Some(volume_mounts.clone());
+        }
+    }
+    jobs_api.create(&PostParams::default(), &upgrade_job).await
+}
+
+pub async fn delete_volume_upgrade_job(
+    client: Client,
+    name: &str,
+    ordinal: Option<i32>,
+    namespace: &str,
+) -> Result<(), Error> {
+    delete(
+        client,
+        &pg_upgrade_job_name(name, &upgrade_node(ordinal)),
+        namespace,
+    )
+    .await
+}
+
+/// Set `parameters` with `ALTER SYSTEM` on every node in `hosts` and reload their configuration
+///
+/// Parameters set before and no longer listed are reset. The Job records the parameters it
+/// applies; a Job left over from an earlier set is deleted and `None` returned until it is gone.
+pub async fn reload_parameters(
+    client: Client,
+    name: &str,
+    parameters: &BTreeMap<String, String>,
+    hosts: Vec<String>,
+    spec: &CitusClusterSpec,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<Option<Job>, Error> {
+    let jobs_api: Api<Job> = Api::namespaced(client.clone(), namespace);
+    let job_name = reload_job_name(name);
+    let rendered = postgresql::render(parameters);
+    if let Some(job) = jobs_api.get_opt(&job_name).await? {
+        let applies = job
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|a| a.get(PARAMETERS_ANNOTATION));
+        if applies == Some(&rendered) {
+            return Ok(Some(job));
+        }
+        if job.metadata.deletion_timestamp.is_none() {
+            delete(client, &job_name, namespace).await?;
+        }
+        return Ok(None);
+    }
+
+    let statements = std::iter::once("ALTER SYSTEM RESET ALL".to_owned())
+        .chain(parameters.iter().map(|(parameter, value)| {
+            format!(
+                "ALTER SYSTEM SET {parameter} = {}",
+                postgresql::quote(value)
+            )
+        }))
+        .chain(std::iter::once("SELECT pg_reload_conf()".to_owned()))
+        .collect();
+    let reload_job = psql_each_job(
+        name,
+        ObjectMeta {
+            name: Some(job_name),
+            owner_references: Some(vec![oref.clone()]),
+            annotations: Some(BTreeMap::from([(
+                PARAMETERS_ANNOTATION.to_owned(),
+                rendered,
+            )])),
+            ..ObjectMeta::default()
+        },
+        format!("{name}-reload"),
+        hosts,
+        statements,
+        spec,
+    );
+    jobs_api
+        .create(&PostParams::default(), &reload_job)
+        .await
+        .map(Some)
+}
+
+pub async fn delete_reload_job(client: Client, name: &str, namespace: &str) -> Result<(), Error> {
+    delete(client, &reload_job_name(name), namespace).await
+}
+
+/// Delete a finished Job along with its pods
+async fn delete(client: Client, job_name: &str, namespace: &str) -> Result<(), Error> {
+    let jobs_api: Api<Job> = Api::namespaced(client, namespace);
+    let dparams = DeleteParams {
+        propagation_policy: Some(PropagationPolicy::Background),
+        ..DeleteParams::default()
+    };
+    jobs_api.delete(job_name, &dparams).await?;
+
+    Ok(())
+}
+
+/// Describe why `job` failed, along with the last lines logged by its most recent pod
+pub async fn failure(client: Client, job: &Job, namespace: &str) -> String {
+    let job_name = job.metadata.name.clone().unwrap_or_default();
+    let pod_api: Api<Pod> = Api::namespaced(client, namespace);
+    let pods = pod_api
+        .list(&ListParams::default().labels(&format!("job-name={job_name}")))
+        .await
+        .map(|pods| pods.items)
+        .unwrap_or_default();
+    let latest = pods
+        .iter()
+        .max_by_key(|pod| pod.metadata.creation_timestamp.clone())
+        .and_then(|pod| pod.metadata.name.clone());
+
+    let logs = match latest {
+        Some(pod) => pod_api
+            .logs(
+                &pod,
+                &LogParams {
+                    tail_lines: Some(FAILURE_LOG_LINES),
+                    ..LogParams::default()
+                },
+            )
+            .await
+            .ok(),
+        None => None,
+    };
+    match logs.map(|l| l.trim().to_owned()).filter(|l| !l.is_empty()) {
+        Some(logs) => format!("Job {job_name} failed: {logs}"),
+        None => format!("Job {job_name} failed"),
+    }
+}
+
+pub fn succeeded(job: &Job) -> bool {
+    job.status
+        .as_ref()
+        .and_then(|s| s.succeeded)
+        .is_some_and(|n| n > 0)
+}
+
+pub fn failed(job: &Job) -> bool {
+    job.status
+        .as_ref()
+        .and_then(|s| s.conditions.as_ref())
+        .is_some_and(|conditions| {
+            conditions
+                .iter()
+                .any(|c| c.type_ == "Failed" && c.status == "True")
+        })
+}
+
+fn migrate_job_name(name: &str) -> String {
+    format!("{name}-migrate-coordinator")
+}
+
+fn update_job_name(name: &str, node: &str) -> String {
+    format!("{name}-update-citus-{node}")
+}
+
+fn pg_upgrade_job_name(name: &str, step: &str) -> String {
+    format!("{name}-pg-upgrade-{step}")
+}
+
+fn reload_job_name(name: &str) -> String {
+    format!("{name}-reload")
+}
+
+fn upgrade_node(ordinal: Option<i32>) -> String {
+    match ordinal {
+        Some(i) => format!("worker-{i}"),
+        None => "coordinator".to_owned(),
+    }
+}
+
+/// A Job that runs each statement against `host` with psql, stopping on the first error
+///
+/// Statements are passed as separate `-c` arguments so each runs in its own transaction,
+/// which the shard rebalancer functions require.
+fn psql_job(
+    name: &str,
+    metadata: ObjectMeta,
+    container_name: String,
+    host: String,
+    statements: Vec<String>,
+    spec: &CitusClusterSpec,
+) -> Job {
+    let mut command = vec![
+        "psql".to_owned(),
+        "-v".to_owned(),
+        "ON_ERROR_STOP=1".to_owned(),
+    ];
+    for statement in statements {
+        command.push("-c".to_owned());
+        command.push(statement);
+    }
+
+    job(name, metadata, container_name, host, command, spec)
+}
+
+/// A Job that runs each statement against every node in `hosts` in turn with psql, stopping
+/// on the first error
+fn psql_each_job(
+    name: &str,
+    metadata: ObjectMeta,
+    container_name: String,
+    hosts: Vec<String>,
+    statements: Vec<String>,
+    spec: &CitusClusterSpec,
+) -> Job {
+    // The statements are handed to the script as its arguments, so they need no quoting
+    let mut command = vec![
+        "bash".to_owned(),
+        "-c".to_owned(),
+        format!(
+            r#"set -e; for host in {}; do psql -h "$host" -v ON_ERROR_STOP=1 "$@"; done"#,
+            hosts.join(" ")
+        ),
+        "psql-each".to_owned(),
+    ];
+    for statement in statements {
+        command.push("-c".to_owned());
+        command.push(statement);
+    }
+
+    let host = hosts.first().cloned().unwrap_or_default();
+    job(name, metadata, container_name, host, command, spec)
+}
+
+/// A Job that runs `command` with the connection settings for `host` in its environment
+fn job(
+    name: &str,
+    metadata: ObjectMeta,
+    container_name: String,
+    host: String,
+    command: Vec<String>,
+    spec: &CitusClusterSpec,
+) -> Job {
+    Job {
+        metadata,
         spec: Some(JobSpec {
             template: PodTemplateSpec {
                 spec: Some(PodSpec {
                     restart_policy: Some("OnFailure".to_owned()),
+                    image_pull_secrets: spec.image_pull_secrets.clone(),
                     containers: vec![Container {
-                        name: format!("{name}-init-worker"),
-                        image: Some("citusdata/citus:12.1".to_owned()),
-                        command: Some(vec![
-                            "bash".to_owned(),
-                            "-c".to_owned(),
-                            format!("psql -c \"{}\"", (0..cnt)
-                                .map(|i| {
-                                    let wqname = workers::qname(name);
-                                    format!(
-                                        r#"SELECT * from master_add_node('{wqname}-{i}.{wqname}', 5432)"#
-                                    )
-                                })
-                                .collect::<Vec<_>>()
-                                .join(";")),
-                        ]),
-                        image_pull_policy: Some("IfNotPresent".to_owned()),
+                        name: container_name,
+                        image: Some(spec.image.clone()),
+                        command: Some(command),
+                        image_pull_policy: Some(spec.image_pull_policy.clone()),
                         env: Some(vec![
                             EnvVar {
                                 name: "PGHOST".to_owned(),
-                                value: Some(format!("{name}.{namespace}")),
+                                value: Some(host),
                                 ..EnvVar::default()
                             },
                             EnvVar {
@@ -49,11 +601,7 @@ pub async fn register_workers(
                                 value: Some("postgres".to_owned()),
                                 ..EnvVar::default()
                             },
-                            EnvVar {
-                                name: "PGPASSWORD".to_owned(),
-                                value: Some("yourpassword".to_owned()),
-                                ..EnvVar::default()
-                            },
+                            credentials::env("PGPASSWORD", name, spec),
                         ]),
                         ..Container::default()
                     }],
@@ -61,11 +609,9 @@ pub async fn register_workers(
                 }),
                 ..PodTemplateSpec::default()
             },
+            ttl_seconds_after_finished: Some(JOB_TTL_SECONDS),
             ..JobSpec::default()
         }),
         ..Job::default()
-    };
-
-    let jobs_api: Api<Job> = Api::namespaced(client.clone(), namespace);
-    jobs_api.create(&PostParams::default(), &init_job).await
+    }
 }
@@ -1,6 +1,18 @@
 pub mod cluster;
 pub mod crd;
+pub mod credentials;
 pub mod jobs;
 pub mod master;
+pub mod membership;
+pub mod pg_upgrade;
+pub mod postgresql;
+pub mod resize;
+pub mod resources;
+pub mod scheduling;
+pub mod status;
 pub mod storage;
+pub mod upgrade;
 pub mod workers;
+
+/// Field manager for every object the operator server-side applies
+pub const FIELD_MANAGER: &str = "citus-operator";
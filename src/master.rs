@@ -1,78 +1,186 @@
 use std::collections::BTreeMap;
 
-use k8s_openapi::api::apps::v1::{Deployment, DeploymentSpec};
+use k8s_openapi::api::apps::v1::{Deployment, StatefulSet, StatefulSetSpec};
 use k8s_openapi::api::core::v1::{
-    Container, ContainerPort, EnvVar, PodSpec, PodTemplateSpec, Service, ServicePort, ServiceSpec,
+    Container, ContainerPort, PersistentVolumeClaim, Pod, PodSpec, PodTemplateSpec, Service,
+    ServicePort, ServiceSpec, VolumeMount,
 };
-use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, ObjectMeta};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, ObjectMeta, OwnerReference};
 use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
-use kube::api::{DeleteParams, PostParams};
+use kube::api::{DeleteParams, ListParams, Patch, PatchParams};
 use kube::{Api, Client, Error};
 
-pub async fn deploy(client: Client, name: &str, namespace: &str) -> Result<Deployment, Error> {
+use crate::{cluster, credentials, postgresql, resources, scheduling, storage, FIELD_MANAGER};
+use crate::crd::CitusClusterSpec;
+
+/// Apply the coordinator StatefulSet, which keeps the Citus metadata on its own volume
+///
+/// `replicas` is 1 except while the coordinator is stopped for a major upgrade.
+pub async fn deploy(
+    client: Client,
+    name: &str,
+    replicas: i32,
+    claims: Vec<PersistentVolumeClaim>,
+    spec: &CitusClusterSpec,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<StatefulSet, Error> {
     let mut master_labels: BTreeMap<String, String> = BTreeMap::new();
     master_labels.insert("app".to_owned(), name.to_owned());
-    master_labels.insert("node".to_owned(), "master".to_owned());
+    master_labels.insert("node".to_owned(), "coordinator".to_owned());
+
+    let mut env = vec![
+        credentials::env("POSTGRES_PASSWORD", name, spec),
+        storage::coordinator_data_env(),
+    ];
+    let mut volume_mounts = vec![
+        VolumeMount {
+            mount_path: storage::DATA_DIR.to_owned(),
+            name: name.to_owned(),
+            ..Default::default()
+        },
+        postgresql::volume_mount(),
+    ];
+    if storage::has_wal(name, &claims) {
+        env.push(storage::wal_env());
+        volume_mounts.push(storage::wal_volume_mount(name));
+    }
 
-    let mut master_node_selector: BTreeMap<String, String> = BTreeMap::new();
-    master_node_selector.insert("citus-cluster-tag".to_owned(), "master".to_owned());
+    let pod_annotations =
+        postgresql::pod_annotations(client.clone(), name, spec, namespace).await?;
 
-    let deployment: Deployment = Deployment {
+    let ss: StatefulSet = StatefulSet {
         metadata: ObjectMeta {
             name: Some(name.to_owned()),
             namespace: Some(namespace.to_owned()),
             labels: Some(master_labels.clone()),
+            owner_references: Some(vec![oref.clone()]),
             ..ObjectMeta::default()
         },
-        spec: Some(DeploymentSpec {
-            replicas: Some(1),
+        spec: Some(StatefulSetSpec {
+            service_name: name.to_owned(),
+            replicas: Some(replicas),
             selector: LabelSelector {
                 match_expressions: None,
                 match_labels: Some(master_labels.clone()),
             },
             template: PodTemplateSpec {
                 spec: Some(PodSpec {
-                    node_selector: Some(master_node_selector),
+                    image_pull_secrets: spec.image_pull_secrets.clone(),
+                    volumes: Some(vec![postgresql::volume(name)]),
                     containers: vec![Container {
                         name: name.to_owned(),
-                        image: Some("citusdata/citus:12.1".to_owned()),
-                        image_pull_policy: Some("IfNotPresent".to_owned()),
+                        image: Some(spec.image.clone()),
+                        image_pull_policy: Some(spec.image_pull_policy.clone()),
+                        args: Some(postgresql::args(spec, spec.coordinator.as_ref())),
+                        resources: resources::requirements(spec.coordinator.as_ref()),
                         ports: Some(vec![ContainerPort {
                             container_port: 5432,
                             ..ContainerPort::default()
                         }]),
-                        env: Some(vec![EnvVar {
-                            name: "POSTGRES_PASSWORD".to_owned(),
-                            value: Some("yourpassword".to_owned()),
-                            ..EnvVar::default()
-                        }]),
+                        env: Some(env),
+                        volume_mounts: Some(volume_mounts),
                         ..Container::default()
                     }],
-                    ..PodSpec::default()
+                    ..scheduling::pod_spec(spec.coordinator.as_ref(), None)
                 }),
                 metadata: Some(ObjectMeta {
                     labels: Some(master_labels.clone()),
+                    annotations: Some(pod_annotations),
                     ..ObjectMeta::default()
                 }),
             },
-            ..DeploymentSpec::default()
+            volume_claim_templates: Some(claims),
+            persistent_volume_claim_retention_policy: Some(storage::retention_policy(spec)),
+            ..StatefulSetSpec::default()
         }),
-        ..Deployment::default()
+        ..StatefulSet::default()
     };
 
-    let deployment_api: Api<Deployment> = Api::namespaced(client.clone(), namespace);
-    deployment_api
-        .create(&PostParams::default(), &deployment)
+    let ss_api: Api<StatefulSet> = Api::namespaced(client.clone(), namespace);
+    ss_api
+        .patch(
+            name,
+            &PatchParams::apply(FIELD_MANAGER).force(),
+            &Patch::Apply(&ss),
+        )
         .await
 }
 
-pub async fn expose(client: Client, name: &str, namespace: &str) -> Result<Service, Error> {
+/// Fetch the live coordinator StatefulSet, if one exists
+pub async fn get(
+    client: Client,
+    name: &str,
+    namespace: &str,
+) -> Result<Option<StatefulSet>, Error> {
+    let ss_api: Api<StatefulSet> = Api::namespaced(client, namespace);
+    ss_api.get_opt(name).await
+}
+
+/// Fetch the Deployment that ran the coordinator before it moved to a StatefulSet, if any
+pub async fn legacy(
+    client: Client,
+    name: &str,
+    namespace: &str,
+) -> Result<Option<Deployment>, Error> {
+    let deployment_api: Api<Deployment> = Api::namespaced(client, namespace);
+    deployment_api.get_opt(name).await
+}
+
+/// Pod IPs of the legacy coordinator Deployment and of the coordinator StatefulSet, once both
+/// have one
+pub async fn migration_hosts(
+    client: Client,
+    name: &str,
+    namespace: &str,
+) -> Result<Option<(String, String)>, Error> {
+    let pod_api: Api<Pod> = Api::namespaced(client, namespace);
+    let pod_ip = |pod: &Pod| pod.status.as_ref().and_then(|s| s.pod_ip.clone());
+
+    let legacy_pods = pod_api
+        .list(&ListParams::default().labels(&format!("app={name},node=master")))
+        .await?;
+    let from = legacy_pods.items.iter().find_map(pod_ip);
+    let to = pod_api
+        .get_opt(&format!("{name}-0"))
+        .await?
+        .as_ref()
+        .and_then(pod_ip);
+
+    Ok(from.zip(to))
+}
+
+pub async fn delete_legacy(client: Client, name: &str, namespace: &str) -> Result<(), Error> {
+    let deployment_api: Api<Deployment> = Api::namespaced(client, namespace);
+    match deployment_api.delete(name, &DeleteParams::default()).await {
+        Ok(_) => Ok(()),
+        Err(Error::Api(e)) if e.code == 404 => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Apply the coordinator Service
+///
+/// While a legacy coordinator Deployment is `migrating`, the Service keeps routing to it so
+/// clients never see the new coordinator before its data has been copied over.
+pub async fn expose(
+    client: Client,
+    name: &str,
+    migrating: bool,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<Service, Error> {
     let mut master_labels: BTreeMap<String, String> = BTreeMap::new();
     master_labels.insert("app".to_owned(), name.to_owned());
     master_labels.insert("node".to_owned(), "master".to_owned());
 
     let mut master_selector_labels: BTreeMap<String, String> = BTreeMap::new();
-    master_selector_labels.insert("node".to_owned(), "master".to_owned());
+    master_selector_labels.insert("app".to_owned(), name.to_owned());
+    if migrating {
+        master_selector_labels.insert("node".to_owned(), "master".to_owned());
+    } else {
+        master_selector_labels.insert("node".to_owned(), "coordinator".to_owned());
+    }
 
     let service_api: Api<Service> = Api::namespaced(client.clone(), namespace);
 
@@ -81,6 +189,7 @@ pub async fn expose(client: Client, name: &str, namespace: &str) -> Result<Servi
             name: Some(name.to_owned()),
             namespace: Some(namespace.to_owned()),
             labels: Some(master_labels.clone()),
+            owner_references: Some(vec![oref.clone()]),
             ..ObjectMeta::default()
         },
         spec: Some(ServiceSpec {
@@ -95,16 +204,44 @@ pub async fn expose(client: Client, name: &str, namespace: &str) -> Result<Servi
         }),
         ..Service::default()
     };
-    service_api.create(&PostParams::default(), &svc).await
+    service_api
+        .patch(
+            name,
+            &PatchParams::apply(FIELD_MANAGER).force(),
+            &Patch::Apply(&svc),
+        )
+        .await
 }
 
-pub async fn delete(client: Client, name: &str, namespace: &str) -> Result<(), Error> {
+/// Delete the coordinator and its Service, returning those that could not be deleted
+pub async fn delete(client: Client, name: &str, namespace: &str) -> Vec<String> {
+    let ss_api: Api<StatefulSet> = Api::namespaced(client.clone(), namespace);
     let deployment_api: Api<Deployment> = Api::namespaced(client.clone(), namespace);
     let service_api: Api<Service> = Api::namespaced(client.clone(), namespace);
 
-    let dparams = DeleteParams::default();
-    deployment_api.delete(name, &dparams).await?;
-    service_api.delete(name, &dparams).await?;
+    [
+        cluster::delete_child(&ss_api, name).await,
+        cluster::delete_child(&deployment_api, name).await,
+        cluster::delete_child(&service_api, name).await,
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+pub fn ready(ss: &StatefulSet) -> bool {
+    ss.status
+        .as_ref()
+        .and_then(|s| s.ready_replicas)
+        .is_some_and(|n| n > 0)
+}
+
+/// Fully qualified DNS name of the coordinator Service
+pub(crate) fn host(name: &str, spec: &CitusClusterSpec, namespace: &str) -> String {
+    format!("{name}.{namespace}.svc.{}", spec.cluster_domain)
+}
 
-    Ok(())
+/// Address clients use to reach the coordinator
+pub fn endpoint(name: &str, namespace: &str) -> String {
+    format!("{name}.{namespace}.svc:5432")
 }
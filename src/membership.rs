@@ -0,0 +1,310 @@
+use std::ops::Range;
+use std::time::Duration;
+
+use k8s_openapi::api::apps::v1::StatefulSet;
+use kube::Client;
+use tokio_postgres::NoTls;
+
+use crate::crd::CitusClusterSpec;
+use crate::{credentials, master, workers};
+
+const NO_PASSWORD: &str = "The superuser password could not be read";
+
+/// A node as recorded in `pg_dist_node` on the coordinator
+pub struct Node {
+    pub id: i32,
+    pub host: String,
+    pub port: i32,
+    pub active: bool,
+    /// Whether the node holds an up to date copy of the Citus metadata
+    pub metadata_synced: bool,
+}
+
+/// What the coordinator reported about its workers
+pub struct Membership {
+    /// Whether every registered worker was known, active and had its metadata synced
+    pub metadata_synced: bool,
+    /// Why the coordinator could not be reached or a change failed
+    pub failure: Option<String>,
+}
+
+/// A connection to the coordinator for managing which workers belong to the cluster
+pub struct Coordinator {
+    client: tokio_postgres::Client,
+}
+
+impl Coordinator {
+    /// Connect to the coordinator at `host` as the superuser
+    pub async fn connect(host: &str, password: &str) -> Result<Self, tokio_postgres::Error> {
+        let (client, connection) = tokio_postgres::Config::new()
+            .host(host)
+            .port(5432)
+            .user("postgres")
+            .password(password)
+            .dbname("postgres")
+            .connect_timeout(Duration::from_secs(5))
+            .connect(NoTls)
+            .await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                eprintln!("Coordinator connection error: {e}");
+            }
+        });
+
+        Ok(Coordinator { client })
+    }
+
+    /// Worker nodes known to the coordinator
+    pub async fn nodes(&self) -> Result<Vec<Node>, tokio_postgres::Error> {
+        let rows = self
+            .client
+            .query(
+                "SELECT nodeid, nodename, nodeport, isactive, hasmetadata AND metadatasynced \
+                 FROM pg_dist_node \
+                 WHERE groupid <> 0 AND noderole = 'primary'",
+                &[],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| Node {
+                id: row.get(0),
+                host: row.get(1),
+                port: row.get(2),
+                active: row.get(3),
+                metadata_synced: row.get(4),
+            })
+            .collect())
+    }
+
+    /// Names of the settings that only take effect when the server starts
+    pub async fn postmaster_parameters(&self) -> Result<Vec<String>, tokio_postgres::Error> {
+        let rows = self
+            .client
+            .query(
+                "SELECT name FROM pg_settings WHERE context = 'postmaster' ORDER BY name",
+                &[],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Add a worker and copy the metadata to it, returning its node id
+    pub async fn add_node(&self, host: &str, port: i32) -> Result<i32, tokio_postgres::Error> {
+        let row = self
+            .client
+            .query_one("SELECT citus_add_node($1, $2)", &[&host, &port])
+            .await?;
+        Ok(row.get(0))
+    }
+
+    /// Activate a worker that was added but is not active, e.g. one that was disabled
+    pub async fn activate_node(&self, host: &str, port: i32) -> Result<(), tokio_postgres::Error> {
+        self.client
+            .execute("SELECT citus_activate_node($1, $2)", &[&host, &port])
+            .await
+            .map(|_| ())
+    }
+
+    /// Point the node `id` at a new address, keeping its shard placements
+    pub async fn update_node(
+        &self,
+        id: i32,
+        host: &str,
+        port: i32,
+    ) -> Result<(), tokio_postgres::Error> {
+        self.client
+            .execute("SELECT citus_update_node($1, $2, $3)", &[&id, &host, &port])
+            .await
+            .map(|_| ())
+    }
+
+    /// Move every shard placement off a worker, waiting until the moves have finished
+    pub async fn drain_node(&self, host: &str, port: i32) -> Result<(), tokio_postgres::Error> {
+        self.client
+            .execute("SELECT citus_drain_node($1, $2)", &[&host, &port])
+            .await
+            .map(|_| ())
+    }
+
+    /// Remove a worker; this fails while it still holds shard placements
+    pub async fn remove_node(&self, host: &str, port: i32) -> Result<(), tokio_postgres::Error> {
+        self.client
+            .execute("SELECT citus_remove_node($1, $2)", &[&host, &port])
+            .await
+            .map(|_| ())
+    }
+}
+
+/// Add the worker ordinals in `ordinals` to the coordinator
+///
+/// Ordinals the coordinator already knows under any address are left to [`reconcile`].
+/// Returns the reason if the coordinator could not be reached or refused a worker.
+pub async fn register(
+    client: Client,
+    name: &str,
+    ordinals: Range<i32>,
+    spec: &CitusClusterSpec,
+    namespace: &str,
+) -> Result<Option<String>, kube::Error> {
+    let Some(password) = credentials::password(client, name, spec, namespace).await? else {
+        return Ok(Some(NO_PASSWORD.to_owned()));
+    };
+    let coordinator_host = master::host(name, spec, namespace);
+
+    let add = async {
+        let coordinator = Coordinator::connect(&coordinator_host, &password).await?;
+        let nodes = coordinator.nodes().await?;
+        for i in ordinals.clone() {
+            if !nodes.iter().any(|n| ordinal(name, &n.host) == Some(i)) {
+                let host = workers::host(name, i, spec, namespace);
+                coordinator.add_node(&host, 5432).await?;
+            }
+        }
+        Ok::<(), tokio_postgres::Error>(())
+    };
+
+    Ok(add
+        .await
+        .err()
+        .map(|e| format!("Registering workers {ordinals:?} failed: {e}")))
+}
+
+/// Move every shard off the worker ordinals in `ordinals` and remove them from the coordinator
+///
+/// The highest ordinals go first. Draining waits for the shard moves, and picks up the moves
+/// that are left if it is interrupted and run again. Returns the reason if it failed.
+pub async fn drain(
+    client: Client,
+    name: &str,
+    ordinals: Range<i32>,
+    spec: &CitusClusterSpec,
+    namespace: &str,
+) -> Result<Option<String>, kube::Error> {
+    let Some(password) = credentials::password(client, name, spec, namespace).await? else {
+        return Ok(Some(NO_PASSWORD.to_owned()));
+    };
+    let coordinator_host = master::host(name, spec, namespace);
+
+    let remove = async {
+        let coordinator = Coordinator::connect(&coordinator_host, &password).await?;
+        let nodes = coordinator.nodes().await?;
+        for i in ordinals.clone().rev() {
+            if let Some(node) = nodes.iter().find(|n| ordinal(name, &n.host) == Some(i)) {
+                coordinator.drain_node(&node.host, node.port).await?;
+                coordinator.remove_node(&node.host, node.port).await?;
+            }
+        }
+        Ok::<(), tokio_postgres::Error>(())
+    };
+
+    Ok(remove
+        .await
+        .err()
+        .map(|e| format!("Draining workers {ordinals:?} failed: {e}")))
+}
+
+/// Bring the workers known to the coordinator in line with the registered worker ordinals
+///
+/// Registered ordinals missing from `pg_dist_node` are added, inactive ones activated, and
+/// ones recorded under another address of the same pod updated in place. Nodes for ordinals
+/// that are neither registered nor running any more are removed. The metadata only counts as
+/// synced on a pass that found nothing to repair.
+pub async fn reconcile(
+    client: Client,
+    name: &str,
+    workers: &StatefulSet,
+    spec: &CitusClusterSpec,
+    namespace: &str,
+) -> Result<Membership, kube::Error> {
+    let Some(password) = credentials::password(client, name, spec, namespace).await? else {
+        return Ok(Membership {
+            metadata_synced: false,
+            failure: Some(NO_PASSWORD.to_owned()),
+        });
+    };
+    let coordinator_host = master::host(name, spec, namespace);
+    let registered = workers::registered(workers);
+    let running = workers::replicas(workers);
+
+    let repair = async {
+        let coordinator = Coordinator::connect(&coordinator_host, &password).await?;
+        let nodes = coordinator.nodes().await?;
+        let mut synced = true;
+        for i in 0..registered {
+            let host = workers::host(name, i, spec, namespace);
+            match nodes.iter().find(|n| ordinal(name, &n.host) == Some(i)) {
+                None => {
+                    coordinator.add_node(&host, 5432).await?;
+                    synced = false;
+                }
+                Some(node) if node.host != host || node.port != 5432 => {
+                    coordinator.update_node(node.id, &host, 5432).await?;
+                    synced = false;
+                }
+                Some(node) if !node.active => {
+                    coordinator.activate_node(&host, 5432).await?;
+                    synced = false;
+                }
+                Some(node) => synced &= node.metadata_synced,
+            }
+        }
+        for node in &nodes {
+            if ordinal(name, &node.host).is_some_and(|i| i >= registered.max(running)) {
+                coordinator.remove_node(&node.host, node.port).await?;
+            }
+        }
+        Ok::<bool, tokio_postgres::Error>(synced)
+    };
+
+    Ok(match repair.await {
+        Ok(metadata_synced) => Membership {
+            metadata_synced,
+            failure: None,
+        },
+        Err(e) => Membership {
+            metadata_synced: false,
+            failure: Some(format!("Managing workers on the coordinator failed: {e}")),
+        },
+    })
+}
+
+/// The worker ordinal a host in `pg_dist_node` belongs to, whichever DNS name it was added under
+fn ordinal(name: &str, host: &str) -> Option<i32> {
+    let pod = host.split('.').next()?;
+    pod.strip_prefix(&format!("{}-", workers::qname(name)))?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordinal_of_short_host() {
+        assert_eq!(ordinal("citus", "citus-workers-2"), Some(2));
+    }
+
+    #[test]
+    fn ordinal_of_fully_qualified_host() {
+        assert_eq!(
+            ordinal(
+                "citus",
+                "citus-workers-10.citus-workers.default.svc.cluster.local"
+            ),
+            Some(10)
+        );
+    }
+
+    #[test]
+    fn ordinal_of_other_hosts() {
+        assert_eq!(
+            ordinal("citus", "citus-0.citus.default.svc.cluster.local"),
+            None
+        );
+        assert_eq!(ordinal("citus", "other-workers-1"), None);
+        assert_eq!(ordinal("citus", "citus-workers-x"), None);
+    }
+}
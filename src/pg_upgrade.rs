@@ -0,0 +1,319 @@
+use k8s_openapi::api::apps::v1::StatefulSet;
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::PersistentVolumeClaim;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+use kube::{Client, Error};
+
+use crate::cluster::CitusDeployment;
+use crate::crd::{CitusClusterSpec, MajorUpgradeStatus, MajorUpgradeStep};
+use crate::{jobs, master, storage, upgrade, workers};
+
+/// The major upgrade to drive on this pass, if one is under way or `spec` asks for one
+///
+/// An upgrade starts when `spec.majorUpgrade` is set and the coordinator runs an image other
+/// than `spec.image`. Once started, it is resumed from `progress` until it finishes.
+pub fn pending(
+    spec: &CitusClusterSpec,
+    progress: Option<&MajorUpgradeStatus>,
+    master: Option<&StatefulSet>,
+    workers: Option<&StatefulSet>,
+) -> Option<MajorUpgradeStatus> {
+    if let Some(progress) = progress {
+        return Some(progress.clone());
+    }
+    spec.major_upgrade.as_ref()?;
+    let from_image = upgrade::image(master?)?;
+    if from_image == spec.image {
+        return None;
+    }
+
+    Some(MajorUpgradeStatus {
+        from_image: from_image.to_owned(),
+        image: spec.image.clone(),
+        step: MajorUpgradeStep::StoppingWrites,
+        workers: workers.map(workers::replicas).unwrap_or_default(),
+        failure: None,
+    })
+}
+
+/// Drive a major upgrade as far as it can go on this pass
+///
+/// Writes are stopped and the Citus metadata prepared on every node, the coordinator and the
+/// workers are scaled down, and pg_upgrade is run against each of their volumes. The nodes
+/// are then restarted on the new image and the Citus metadata restored. The step reached is
+/// returned in the deployment; a step whose Job failed is retried once that Job is deleted.
+pub async fn reconcile(
+    client: Client,
+    name: &str,
+    spec: &CitusClusterSpec,
+    mut progress: MajorUpgradeStatus,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<CitusDeployment, Error> {
+    let mut master = master::get(client.clone(), name, namespace)
+        .await?
+        .unwrap_or_default();
+    let mut workers = workers::get(client.clone(), name, namespace)
+        .await?
+        .unwrap_or_default();
+    let master_claims = claims(name, &master, storage::coordinator_size(spec), spec);
+    let worker_claims = claims(name, &workers, storage::worker_size(spec), spec);
+    let hosts: Vec<String> = std::iter::once(master::host(name, spec, namespace))
+        .chain((0..progress.workers).map(|i| workers::host(name, i, spec, namespace)))
+        .collect();
+
+    let Some(major_upgrade) = &spec.major_upgrade else {
+        progress.failure = Some(format!(
+            "majorUpgrade must stay set until the upgrade to {} has finished",
+            progress.image
+        ));
+        return Ok(deployment(master, workers, Some(progress)));
+    };
+    progress.failure = None;
+
+    loop {
+        match progress.step {
+            MajorUpgradeStep::StoppingWrites => {
+                let job = jobs::prepare_pg_upgrade(
+                    client.clone(),
+                    name,
+                    hosts.clone(),
+                    spec,
+                    oref,
+                    namespace,
+                )
+                .await?;
+                if !finished(&job, &mut progress, "Preparing the nodes for pg_upgrade") {
+                    break;
+                }
+                jobs::delete_pg_upgrade_job(client.clone(), name, "prepare", namespace).await?;
+                progress.step = MajorUpgradeStep::Stopping;
+            }
+            MajorUpgradeStep::Stopping => {
+                master = master::deploy(
+                    client.clone(),
+                    name,
+                    0,
+                    master_claims.clone(),
+                    spec,
+                    oref,
+                    namespace,
+                )
+                .await?;
+                workers = workers::deploy(
+                    client.clone(),
+                    name,
+                    0,
+                    worker_claims.clone(),
+                    spec,
+                    oref,
+                    namespace,
+                )
+                .await?;
+                if running_pods(&master) > 0 || running_pods(&workers) > 0 {
+                    break;
+                }
+                progress.step = MajorUpgradeStep::Upgrading;
+            }
+            MajorUpgradeStep::Upgrading => {
+                let ordinals: Vec<Option<i32>> = std::iter::once(None)
+                    .chain((0..progress.workers).map(Some))
+                    .collect();
+                let mut done = true;
+                for ordinal in ordinals.iter().copied() {
+                    let job = jobs::pg_upgrade(
+                        client.clone(),
+                        name,
+                        ordinal,
+                        major_upgrade,
+                        spec,
+                        oref,
+                        namespace,
+                    )
+                    .await?;
+                    let volume = match ordinal {
+                        Some(i) => format!("Upgrading the volume of worker {i}"),
+                        None => "Upgrading the coordinator volume".to_owned(),
+                    };
+                    done &= finished(&job, &mut progress, &volume);
+                }
+                if !done {
+                    break;
+                }
+                for ordinal in ordinals {
+                    jobs::delete_volume_upgrade_job(client.clone(), name, ordinal, namespace)
+                        .await?;
+                }
+                progress.step = MajorUpgradeStep::Restarting;
+            }
+            MajorUpgradeStep::Restarting => {
+                master = master::deploy(
+                    client.clone(),
+                    name,
+                    1,
+                    master_claims.clone(),
+                    spec,
+                    oref,
+                    namespace,
+                )
+                .await?;
+                workers = workers::deploy(
+                    client.clone(),
+                    name,
+                    progress.workers,
+                    worker_claims.clone(),
+                    spec,
+                    oref,
+                    namespace,
+                )
+                .await?;
+                if !master::ready(&master) || workers::ready_replicas(&workers) < progress.workers {
+                    break;
+                }
+                progress.step = MajorUpgradeStep::Finishing;
+            }
+            MajorUpgradeStep::Finishing => {
+                let job = jobs::finish_pg_upgrade(
+                    client.clone(),
+                    name,
+                    hosts.clone(),
+                    spec,
+                    oref,
+                    namespace,
+                )
+                .await?;
+                if !finished(&job, &mut progress, "Restoring the Citus metadata") {
+                    break;
+                }
+                jobs::delete_pg_upgrade_job(client.clone(), name, "finish", namespace).await?;
+                return Ok(deployment(master, workers, None));
+            }
+        }
+    }
+
+    Ok(deployment(master, workers, Some(progress)))
+}
+
+/// Whether `job` succeeded, recording `what` as the failure if it failed
+fn finished(job: &Job, progress: &mut MajorUpgradeStatus, what: &str) -> bool {
+    if jobs::failed(job) {
+        progress.failure = Some(format!("{what} failed"));
+    }
+    jobs::succeeded(job)
+}
+
+/// The claim templates a StatefulSet was created with, or those the spec asks for if it is gone
+fn claims(
+    name: &str,
+    ss: &StatefulSet,
+    size: Quantity,
+    spec: &CitusClusterSpec,
+) -> Vec<PersistentVolumeClaim> {
+    let live = storage::live_claims(ss);
+    if !live.is_empty() {
+        return live;
+    }
+    std::iter::once(storage::volume_claim_template(name, size, spec))
+        .chain(storage::wal_claim_template(name, spec))
+        .collect()
+}
+
+fn running_pods(ss: &StatefulSet) -> i32 {
+    ss.status.as_ref().map(|s| s.replicas).unwrap_or_default()
+}
+
+fn deployment(
+    master: StatefulSet,
+    workers: StatefulSet,
+    major_upgrade: Option<MajorUpgradeStatus>,
+) -> CitusDeployment {
+    CitusDeployment {
+        master,
+        workers,
+        degraded: None,
+        upgrade: None,
+        major_upgrade,
+        metadata_synced: false,
+        volume_resizes: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::apps::v1::StatefulSetSpec;
+    use k8s_openapi::api::core::v1::{Container, PodSpec, PodTemplateSpec};
+    use serde_json::json;
+
+    use super::*;
+
+    fn spec(image: &str, major_upgrade: bool) -> CitusClusterSpec {
+        let mut spec = json!({ "workers": 2, "worker_storage": 1, "image": image });
+        if major_upgrade {
+            spec["majorUpgrade"] = json!({ "postgresVersion": 16, "upgradeImage": "upgrade" });
+        }
+        serde_json::from_value(spec).expect("spec")
+    }
+
+    fn stateful_set(image: &str, replicas: i32) -> StatefulSet {
+        StatefulSet {
+            spec: Some(StatefulSetSpec {
+                replicas: Some(replicas),
+                template: PodTemplateSpec {
+                    spec: Some(PodSpec {
+                        containers: vec![Container {
+                            image: Some(image.to_owned()),
+                            ..Container::default()
+                        }],
+                        ..PodSpec::default()
+                    }),
+                    ..PodTemplateSpec::default()
+                },
+                ..StatefulSetSpec::default()
+            }),
+            ..StatefulSet::default()
+        }
+    }
+
+    #[test]
+    fn starts_when_the_image_changes() {
+        let master = stateful_set("citus:11", 1);
+        let workers = stateful_set("citus:11", 3);
+        let progress =
+            pending(&spec("citus:12", true), None, Some(&master), Some(&workers)).expect("upgrade");
+        assert_eq!(progress.from_image, "citus:11");
+        assert_eq!(progress.image, "citus:12");
+        assert_eq!(progress.step, MajorUpgradeStep::StoppingWrites);
+        assert_eq!(progress.workers, 3);
+    }
+
+    #[test]
+    fn not_without_major_upgrade_or_image_change() {
+        let master = stateful_set("citus:11", 1);
+        assert!(pending(&spec("citus:12", false), None, Some(&master), None).is_none());
+        assert!(pending(&spec("citus:11", true), None, Some(&master), None).is_none());
+        assert!(pending(&spec("citus:12", true), None, None, None).is_none());
+    }
+
+    #[test]
+    fn resumes_recorded_progress() {
+        let progress = MajorUpgradeStatus {
+            from_image: "citus:11".to_owned(),
+            image: "citus:12".to_owned(),
+            step: MajorUpgradeStep::Upgrading,
+            workers: 2,
+            failure: None,
+        };
+        // Progress wins even once the coordinator already runs the new image
+        let master = stateful_set("citus:12", 1);
+        assert_eq!(
+            pending(
+                &spec("citus:12", false),
+                Some(&progress),
+                Some(&master),
+                None
+            ),
+            Some(progress)
+        );
+    }
+}
@@ -0,0 +1,511 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use k8s_openapi::api::core::v1::{ConfigMap, ConfigMapVolumeSource, Volume, VolumeMount};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference};
+use kube::api::{Patch, PatchParams};
+use kube::{Api, Client, Error};
+use serde_json::{json, Value};
+
+use crate::crd::{CitusClusterSpec, Role};
+use crate::membership::Coordinator;
+use crate::{cluster, credentials, jobs, master, resources, storage, FIELD_MANAGER};
+
+/// Where the rendered configuration is mounted in every Postgres container
+const CONFIG_DIR: &str = "/etc/postgresql/citus-operator";
+const CONFIG_FILE: &str = "postgresql.conf";
+/// Parameters last applied to the running nodes without a restart
+const RELOADED_ANNOTATION: &str = "citusclusters.jw3.xyz/reloaded-parameters";
+/// Parameters the pods were started with that only take effect on a restart
+const RESTART_ANNOTATION: &str = "citusclusters.jw3.xyz/restart-parameters";
+/// Settings the coordinator reports as only taking effect when the server starts
+const POSTMASTER_ANNOTATION: &str = "citusclusters.jw3.xyz/postmaster-parameters";
+
+/// Parameters known to only take effect when the server starts, relied on until the
+/// coordinator has reported its own
+const RESTART_PARAMETERS: &[&str] = &[
+    "archive_mode",
+    "autovacuum_max_workers",
+    "citus.max_worker_nodes_tracked",
+    "huge_pages",
+    "listen_addresses",
+    "max_connections",
+    "max_files_per_process",
+    "max_locks_per_transaction",
+    "max_pred_locks_per_transaction",
+    "max_prepared_transactions",
+    "max_replication_slots",
+    "max_wal_senders",
+    "max_worker_processes",
+    "port",
+    "shared_buffers",
+    "shared_preload_libraries",
+    "superuser_reserved_connections",
+    "track_activity_query_size",
+    "wal_buffers",
+    "wal_level",
+    "wal_log_hints",
+];
+
+/// Apply the ConfigMap holding the `spec.postgresql.parameters` of a cluster
+///
+/// The rendered file includes the configuration from the data directory first, so the image
+/// defaults such as `shared_preload_libraries` stay in place unless they are overridden. The
+/// coordinator and the workers keep their data directory in different places, and each only
+/// finds its own. When
+/// the ConfigMap is first created every node starts with its parameters, so they are recorded
+/// as applied straight away.
+pub async fn configure(
+    client: Client,
+    name: &str,
+    spec: &CitusClusterSpec,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<ConfigMap, Error> {
+    let cm_api: Api<ConfigMap> = Api::namespaced(client, namespace);
+    let created = cm_api.get_opt(&qname(name)).await?.is_none();
+
+    let parameters = parameters(spec);
+    let mut config = String::new();
+    for data_dir in [storage::DATA_DIR, storage::COORDINATOR_PGDATA] {
+        config.push_str(&format!(
+            "include_if_exists = '{data_dir}/postgresql.conf'\n"
+        ));
+    }
+    for (parameter, value) in &parameters {
+        config.push_str(&format!("{parameter} = {}\n", quote_config(value)));
+    }
+
+    let mut labels: BTreeMap<String, String> = BTreeMap::new();
+    labels.insert("app".to_owned(), name.to_owned());
+
+    let cm = ConfigMap {
+        metadata: ObjectMeta {
+            name: Some(qname(name)),
+            namespace: Some(namespace.to_owned()),
+            labels: Some(labels),
+            owner_references: Some(vec![oref.clone()]),
+            ..ObjectMeta::default()
+        },
+        data: Some(BTreeMap::from([(CONFIG_FILE.to_owned(), config)])),
+        ..ConfigMap::default()
+    };
+    let cm = cm_api
+        .patch(
+            &qname(name),
+            &PatchParams::apply(FIELD_MANAGER).force(),
+            &Patch::Apply(&cm),
+        )
+        .await?;
+
+    if created {
+        let reloadable = reloadable(&parameters, &postmaster(&cm));
+        mark_reloaded(cm_api, name, &render(&reloadable)).await
+    } else {
+        Ok(cm)
+    }
+}
+
+/// Apply changed parameters that do not need a restart to every node in `hosts`
+///
+/// The parameters are set with `ALTER SYSTEM` and the configuration reloaded, so they take
+/// effect without waiting for the kubelet to refresh the mounted ConfigMap. Which parameters
+/// need a restart is first asked of the coordinator; when that changes, it is recorded and
+/// nothing is reloaded until the next pass has rolled the pods. Returns the reason if applying
+/// the parameters failed.
+pub async fn reload(
+    client: Client,
+    name: &str,
+    cm: &ConfigMap,
+    hosts: Vec<String>,
+    spec: &CitusClusterSpec,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<Option<String>, Error> {
+    if let Some(postmaster) = discover(client.clone(), name, spec, namespace).await? {
+        let discovered = postmaster.join(",");
+        let recorded = cm
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|a| a.get(POSTMASTER_ANNOTATION));
+        if recorded != Some(&discovered) {
+            let cm_api: Api<ConfigMap> = Api::namespaced(client, namespace);
+            annotate(cm_api, name, POSTMASTER_ANNOTATION, &discovered).await?;
+            return Ok(None);
+        }
+    }
+
+    let parameters = reloadable(&parameters(spec), &postmaster(cm));
+    let rendered = render(&parameters);
+    let reloaded = cm
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(RELOADED_ANNOTATION));
+    if reloaded == Some(&rendered) {
+        return Ok(None);
+    }
+
+    let Some(job) = jobs::reload_parameters(
+        client.clone(),
+        name,
+        &parameters,
+        hosts,
+        spec,
+        oref,
+        namespace,
+    )
+    .await?
+    else {
+        return Ok(None);
+    };
+    if jobs::failed(&job) {
+        return Ok(Some(format!("Reloading parameters {rendered} failed")));
+    }
+    if !jobs::succeeded(&job) {
+        return Ok(None);
+    }
+
+    let cm_api: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
+    mark_reloaded(cm_api, name, &rendered).await?;
+    jobs::delete_reload_job(client, name, namespace).await?;
+    Ok(None)
+}
+
+/// Server arguments for the Postgres container of a role
+///
+/// Postgres reads its configuration from the mounted ConfigMap, and memory settings derived
+/// from the role's resources are added unless `spec.postgresql.parameters` sets them.
+pub fn args(spec: &CitusClusterSpec, role: Option<&Role>) -> Vec<String> {
+    let parameters = parameters(spec);
+    let mut args = vec![
+        "-c".to_owned(),
+        format!("config_file={CONFIG_DIR}/{CONFIG_FILE}"),
+    ];
+    for (parameter, value) in resources::memory_settings(role) {
+        if !parameters.contains_key(parameter) {
+            args.push("-c".to_owned());
+            args.push(format!("{parameter}={value}"));
+        }
+    }
+    args
+}
+
+/// Pod annotations that change, and so roll the pods, whenever a restart-only parameter does
+pub async fn pod_annotations(
+    client: Client,
+    name: &str,
+    spec: &CitusClusterSpec,
+    namespace: &str,
+) -> Result<BTreeMap<String, String>, Error> {
+    let cm_api: Api<ConfigMap> = Api::namespaced(client, namespace);
+    let postmaster = cm_api
+        .get_opt(&qname(name))
+        .await?
+        .map(|cm| postmaster(&cm))
+        .unwrap_or_else(|| RESTART_PARAMETERS.iter().map(|p| p.to_string()).collect());
+    let restart = parameters(spec)
+        .into_iter()
+        .filter(|(parameter, _)| requires_restart(parameter, &postmaster))
+        .collect();
+    Ok(BTreeMap::from([(
+        RESTART_ANNOTATION.to_owned(),
+        render(&restart),
+    )]))
+}
+
+/// Why some of `spec.postgresql.parameters` are left out, if any have names or values that are
+/// not valid
+pub fn invalid(spec: &CitusClusterSpec) -> Option<String> {
+    let invalid: Vec<String> = spec
+        .postgresql
+        .as_ref()
+        .map(|p| {
+            p.parameters
+                .iter()
+                .filter(|(parameter, value)| !valid(parameter, value))
+                .map(|(parameter, _)| parameter.clone())
+                .collect()
+        })
+        .unwrap_or_default();
+    (!invalid.is_empty())
+        .then(|| format!("Invalid parameter names or values: {}", invalid.join(", ")))
+}
+
+pub fn volume(name: &str) -> Volume {
+    Volume {
+        name: "postgresql-conf".to_owned(),
+        config_map: Some(ConfigMapVolumeSource {
+            name: Some(qname(name)),
+            ..ConfigMapVolumeSource::default()
+        }),
+        ..Volume::default()
+    }
+}
+
+pub fn volume_mount() -> VolumeMount {
+    VolumeMount {
+        name: "postgresql-conf".to_owned(),
+        mount_path: CONFIG_DIR.to_owned(),
+        read_only: Some(true),
+        ..VolumeMount::default()
+    }
+}
+
+/// Delete the ConfigMap, returning it if it could not be deleted
+pub async fn delete(client: Client, name: &str, namespace: &str) -> Option<String> {
+    let cm_api: Api<ConfigMap> = Api::namespaced(client, namespace);
+    cluster::delete_child(&cm_api, &qname(name)).await
+}
+
+async fn mark_reloaded(
+    cm_api: Api<ConfigMap>,
+    name: &str,
+    rendered: &str,
+) -> Result<ConfigMap, Error> {
+    annotate(cm_api, name, RELOADED_ANNOTATION, rendered).await
+}
+
+async fn annotate(
+    cm_api: Api<ConfigMap>,
+    name: &str,
+    annotation: &str,
+    value: &str,
+) -> Result<ConfigMap, Error> {
+    let annotated: Value = json!({
+        "metadata": {
+            "annotations": {
+                annotation: value
+            }
+        }
+    });
+    let patch: Patch<&Value> = Patch::Merge(&annotated);
+    cm_api
+        .patch(&qname(name), &PatchParams::default(), &patch)
+        .await
+}
+
+/// Ask the coordinator which settings only take effect when the server starts
+///
+/// Returns `None` if the coordinator cannot be asked right now.
+async fn discover(
+    client: Client,
+    name: &str,
+    spec: &CitusClusterSpec,
+    namespace: &str,
+) -> Result<Option<Vec<String>>, Error> {
+    let Some(password) = credentials::password(client, name, spec, namespace).await? else {
+        return Ok(None);
+    };
+    let host = master::host(name, spec, namespace);
+    let postmaster = async {
+        Coordinator::connect(&host, &password)
+            .await?
+            .postmaster_parameters()
+            .await
+    };
+    Ok(postmaster.await.ok())
+}
+
+/// The parameters with valid names and values, which are the only ones written to the
+/// configuration
+fn parameters(spec: &CitusClusterSpec) -> BTreeMap<String, String> {
+    spec.postgresql
+        .as_ref()
+        .map(|p| p.parameters.clone())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(parameter, value)| valid(parameter, value))
+        .collect()
+}
+
+fn valid(parameter: &str, value: &str) -> bool {
+    valid_name(parameter) && valid_value(value)
+}
+
+/// Whether `parameter` matches `^[A-Za-z_][A-Za-z0-9_.]*$`, so it can be written unquoted
+fn valid_name(parameter: &str) -> bool {
+    let mut chars = parameter.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Whether `value` fits on one line of the configuration file, which has no escape for line
+/// breaks or other control characters
+fn valid_value(value: &str) -> bool {
+    !value.chars().any(char::is_control)
+}
+
+/// The settings that need a restart: those the coordinator reported, and the known ones
+fn postmaster(cm: &ConfigMap) -> BTreeSet<String> {
+    let reported = cm
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(POSTMASTER_ANNOTATION))
+        .map(|names| names.split(',').map(str::to_owned).collect::<Vec<_>>())
+        .unwrap_or_default();
+    RESTART_PARAMETERS
+        .iter()
+        .map(|p| p.to_string())
+        .chain(reported)
+        .collect()
+}
+
+fn reloadable(
+    parameters: &BTreeMap<String, String>,
+    postmaster: &BTreeSet<String>,
+) -> BTreeMap<String, String> {
+    parameters
+        .iter()
+        .filter(|(parameter, _)| !requires_restart(parameter, postmaster))
+        .map(|(parameter, value)| (parameter.clone(), value.clone()))
+        .collect()
+}
+
+fn requires_restart(parameter: &str, postmaster: &BTreeSet<String>) -> bool {
+    postmaster.contains(&parameter.to_lowercase())
+}
+
+/// Parameters as `name=value` pairs, in a stable order so they can be compared
+pub(crate) fn render(parameters: &BTreeMap<String, String>) -> String {
+    parameters
+        .iter()
+        .map(|(parameter, value)| format!("{parameter}={value}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Quote a parameter value as an SQL string literal
+pub(crate) fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Quote a parameter value as a string in the configuration file, where backslashes escape
+fn quote_config(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "''"))
+}
+
+fn qname(name: &str) -> String {
+    format!("{name}-postgresql")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(parameters: Value) -> CitusClusterSpec {
+        serde_json::from_value(json!({
+            "workers": 1,
+            "worker_storage": 1,
+            "postgresql": { "parameters": parameters }
+        }))
+        .expect("spec")
+    }
+
+    fn map(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(parameter, value)| (parameter.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn valid_names() {
+        assert!(valid_name("work_mem"));
+        assert!(valid_name("_private"));
+        assert!(valid_name("citus.shard_count"));
+        assert!(valid_name("citus.max_worker_nodes_tracked"));
+    }
+
+    #[test]
+    fn injection_shaped_names_are_invalid() {
+        assert!(!valid_name(""));
+        assert!(!valid_name("1abc"));
+        assert!(!valid_name("a'b"));
+        assert!(!valid_name("work mem"));
+        assert!(!valid_name("x = 1; DROP TABLE t"));
+        assert!(!valid_name("x\ninclude = '/etc/passwd'"));
+        assert!(!valid_name("citus-shard_count"));
+    }
+
+    #[test]
+    fn values_with_control_characters_are_invalid() {
+        assert!(valid_value("'quoted' C:\\dir"));
+        assert!(!valid_value("64MB\nshared_preload_libraries = ''"));
+        assert!(!valid_value("64MB\r"));
+        assert!(!valid_value("a\tb"));
+    }
+
+    #[test]
+    fn invalid_parameters_are_reported_and_left_out() {
+        let mixed = spec(json!({
+            "work_mem": "64MB",
+            "a'b": "1",
+            "search_path": "public\nfsync = off",
+        }));
+        assert_eq!(
+            invalid(&mixed).as_deref(),
+            Some("Invalid parameter names or values: a'b, search_path")
+        );
+        assert_eq!(parameters(&mixed), map(&[("work_mem", "64MB")]));
+        assert_eq!(invalid(&spec(json!({ "work_mem": "64MB" }))), None);
+    }
+
+    #[test]
+    fn config_values_escape_quotes_and_backslashes() {
+        assert_eq!(quote_config("64MB"), "'64MB'");
+        assert_eq!(quote_config("it's"), "'it''s'");
+        assert_eq!(quote_config("C:\\dir\\"), "'C:\\\\dir\\\\'");
+    }
+
+    #[test]
+    fn sql_values_only_escape_quotes() {
+        assert_eq!(quote("it's"), "'it''s'");
+        assert_eq!(quote("C:\\dir"), "'C:\\dir'");
+    }
+
+    #[test]
+    fn render_is_sorted() {
+        let rendered = render(&map(&[("work_mem", "64MB"), ("citus.shard_count", "64")]));
+        assert_eq!(rendered, "citus.shard_count=64,work_mem=64MB");
+        assert_eq!(render(&BTreeMap::new()), "");
+    }
+
+    #[test]
+    fn restart_and_reload_split() {
+        let postmaster = BTreeSet::from(["max_connections".to_owned()]);
+        let parameters = map(&[
+            ("max_connections", "200"),
+            ("Max_Connections", "300"),
+            ("work_mem", "64MB"),
+        ]);
+        assert!(requires_restart("max_connections", &postmaster));
+        assert!(requires_restart("MAX_CONNECTIONS", &postmaster));
+        assert!(!requires_restart("work_mem", &postmaster));
+        assert_eq!(
+            reloadable(&parameters, &postmaster),
+            map(&[("work_mem", "64MB")])
+        );
+    }
+
+    #[test]
+    fn reported_postmaster_parameters_add_to_the_known_ones() {
+        let cm = ConfigMap {
+            metadata: ObjectMeta {
+                annotations: Some(BTreeMap::from([(
+                    POSTMASTER_ANNOTATION.to_owned(),
+                    "cron.database_name,shared_buffers".to_owned(),
+                )])),
+                ..ObjectMeta::default()
+            },
+            ..ConfigMap::default()
+        };
+        let postmaster = postmaster(&cm);
+        assert!(postmaster.contains("cron.database_name"));
+        assert!(postmaster.contains("max_connections"));
+        assert!(!postmaster.contains("work_mem"));
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::apps::v1::StatefulSet;
+use k8s_openapi::api::core::v1::{PersistentVolumeClaim, PersistentVolumeClaimStatus};
+use k8s_openapi::api::storage::v1::StorageClass;
+use kube::api::{DeleteParams, Patch, PatchParams, PropagationPolicy};
+use kube::{Api, Client, Error};
+use serde_json::{json, Value};
+
+use crate::crd::{CitusCluster, VolumeResize};
+use crate::{resources, storage, upgrade};
+
+/// Where growing the volumes of a StatefulSet has got to
+pub struct Resize {
+    /// Progress to record in status, while the volumes are growing
+    pub progress: Option<VolumeResize>,
+    /// Whether the StatefulSet is being recreated, so it must not be applied on this pass
+    pub recreating: bool,
+    /// Why the volumes cannot be resized
+    pub failure: Option<String>,
+}
+
+/// Grow the volumes of the StatefulSet `ss_name` of the cluster `name` to the size of `desired`
+///
+/// Every existing claim is expanded in place, which needs a StorageClass that allows volume
+/// expansion. As claim templates cannot change, the StatefulSet is then deleted leaving its pods
+/// running, so it can be applied again with the new template and adopt them. Its annotations
+/// and replica count are recorded in the cluster status with the other `resizes` before it is
+/// deleted, so they survive the gap even if this pass goes no further. The resize is done once
+/// every claim reports the new capacity; claims are not watched, so a cluster with a resize
+/// recorded keeps being requeued until then. Shrinking is refused.
+pub async fn reconcile(
+    client: Client,
+    name: &str,
+    ss_name: &str,
+    live: Option<&StatefulSet>,
+    desired: &PersistentVolumeClaim,
+    resizes: &[VolumeResize],
+    namespace: &str,
+) -> Result<Resize, Error> {
+    let progress = progress(resizes, ss_name);
+    let Some(live) = live else {
+        return Ok(resize(progress.cloned(), false, None));
+    };
+    if live.metadata.deletion_timestamp.is_some() {
+        return Ok(resize(progress.cloned(), true, None));
+    }
+    let Some(live_claim) = storage::live_claim(live) else {
+        return Ok(resize(None, false, None));
+    };
+
+    let template_name = live_claim.metadata.name.clone().unwrap_or_default();
+    let replicas = live
+        .spec
+        .as_ref()
+        .and_then(|s| s.replicas)
+        .unwrap_or_default();
+    let claims: Vec<String> = (0..replicas)
+        .map(|i| storage::claim_name(&template_name, ss_name, i))
+        .collect();
+
+    let pvc_api: Api<PersistentVolumeClaim> = Api::namespaced(client.clone(), namespace);
+    let live_size = storage::size(&live_claim).unwrap_or_default();
+    let size = storage::size(desired).unwrap_or_default();
+    let (Some(live_bytes), Some(bytes)) = (resources::bytes(&live_size), resources::bytes(&size))
+    else {
+        return Ok(resize(None, false, None));
+    };
+
+    if bytes < live_bytes {
+        let reason = format!(
+            "{ss_name} volumes cannot shrink from {} to {}",
+            live_size.0, size.0
+        );
+        return Ok(resize(None, false, Some(reason)));
+    }
+
+    if bytes == live_bytes {
+        // The template is at the new size; wait for every claim to catch up
+        let Some(progress) = progress else {
+            return Ok(resize(None, false, None));
+        };
+        let mut pending = vec![];
+        let mut claim_conditions = BTreeMap::new();
+        for claim in &claims {
+            let status = pvc_api
+                .get_opt(claim)
+                .await?
+                .and_then(|pvc| pvc.status)
+                .unwrap_or_default();
+            let capacity = status
+                .capacity
+                .as_ref()
+                .and_then(|c| c.get("storage"))
+                .and_then(resources::bytes);
+            if capacity.is_none_or(|c| c < bytes) {
+                pending.push(claim.clone());
+                if let Some(condition) = resize_condition(&status) {
+                    claim_conditions.insert(claim.clone(), condition);
+                }
+            }
+        }
+        let progress = (!pending.is_empty()).then(|| VolumeResize {
+            pending_claims: pending,
+            claim_conditions,
+            ..progress.clone()
+        });
+        return Ok(resize(progress, false, None));
+    }
+
+    // A rolling upgrade holds its place in the StatefulSet, which recreating it would lose
+    if upgrade::pending(live) {
+        return Ok(resize(None, false, None));
+    }
+    for claim in &claims {
+        let Some(pvc) = pvc_api.get_opt(claim).await? else {
+            continue;
+        };
+        if !expandable(client.clone(), &pvc).await? {
+            let reason = format!(
+                "{ss_name} volumes cannot grow to {}: the StorageClass of {claim} does not allow volume expansion",
+                size.0
+            );
+            return Ok(resize(None, false, Some(reason)));
+        }
+    }
+    for claim in &claims {
+        if pvc_api.get_opt(claim).await?.is_none() {
+            continue;
+        }
+        let grow: Value = json!({
+            "spec": {
+                "resources": {
+                    "requests": {
+                        "storage": size
+                    }
+                }
+            }
+        });
+        let patch: Patch<&Value> = Patch::Merge(&grow);
+        pvc_api
+            .patch(claim, &PatchParams::default(), &patch)
+            .await?;
+    }
+
+    let progress = VolumeResize {
+        stateful_set: ss_name.to_owned(),
+        size,
+        replicas,
+        annotations: live.metadata.annotations.clone().unwrap_or_default(),
+        pending_claims: claims,
+        claim_conditions: BTreeMap::new(),
+    };
+    record(client.clone(), name, resizes, &progress, namespace).await?;
+
+    let ss_api: Api<StatefulSet> = Api::namespaced(client, namespace);
+    let orphan = DeleteParams {
+        propagation_policy: Some(PropagationPolicy::Orphan),
+        ..DeleteParams::default()
+    };
+    ss_api.delete(ss_name, &orphan).await?;
+
+    Ok(resize(Some(progress), true, None))
+}
+
+/// Record `progress` in the status of the cluster `name`, keeping the other `resizes`
+async fn record(
+    client: Client,
+    name: &str,
+    resizes: &[VolumeResize],
+    progress: &VolumeResize,
+    namespace: &str,
+) -> Result<CitusCluster, Error> {
+    let api: Api<CitusCluster> = Api::namespaced(client, namespace);
+    let volume_resizes: Vec<&VolumeResize> = resizes
+        .iter()
+        .filter(|r| r.stateful_set != progress.stateful_set)
+        .chain(std::iter::once(progress))
+        .collect();
+    let status: Value = json!({
+        "status": {
+            "volumeResizes": volume_resizes
+        }
+    });
+    let patch: Patch<&Value> = Patch::Merge(&status);
+    api.patch_status(name, &PatchParams::default(), &patch)
+        .await
+}
+
+/// Put back the annotations a StatefulSet had before it was recreated
+pub async fn restore(
+    client: Client,
+    progress: &VolumeResize,
+    namespace: &str,
+) -> Result<StatefulSet, Error> {
+    let ss_api: Api<StatefulSet> = Api::namespaced(client, namespace);
+    let annotations: Value = json!({
+        "metadata": {
+            "annotations": progress.annotations
+        }
+    });
+    let patch: Patch<&Value> = Patch::Merge(&annotations);
+    ss_api
+        .patch(&progress.stateful_set, &PatchParams::default(), &patch)
+        .await
+}
+
+/// Whether the StorageClass of `pvc` allows it to be expanded
+async fn expandable(client: Client, pvc: &PersistentVolumeClaim) -> Result<bool, Error> {
+    let Some(class) = pvc
+        .spec
+        .as_ref()
+        .and_then(|s| s.storage_class_name.as_ref())
+    else {
+        return Ok(false);
+    };
+    let sc_api: Api<StorageClass> = Api::all(client);
+    Ok(sc_api
+        .get_opt(class)
+        .await?
+        .and_then(|sc| sc.allow_volume_expansion)
+        .unwrap_or(false))
+}
+
+/// The resize condition a claim reports, with its message if it has one
+///
+/// FileSystemResizePending means the volume has grown but its filesystem only will once the
+/// pod using it restarts.
+fn resize_condition(status: &PersistentVolumeClaimStatus) -> Option<String> {
+    let condition = status
+        .conditions
+        .as_ref()?
+        .iter()
+        .find(|c| c.status == "True")?;
+    Some(match condition.message.as_deref() {
+        Some(message) if !message.is_empty() => format!("{} ({message})", condition.type_),
+        _ => condition.type_.clone(),
+    })
+}
+
+fn resize(progress: Option<VolumeResize>, recreating: bool, failure: Option<String>) -> Resize {
+    Resize {
+        progress,
+        recreating,
+        failure,
+    }
+}
+
+/// The progress recorded for the StatefulSet `ss_name`, if its volumes are being resized
+pub fn progress<'a>(resizes: &'a [VolumeResize], ss_name: &str) -> Option<&'a VolumeResize> {
+    resizes.iter().find(|r| r.stateful_set == ss_name)
+}
@@ -0,0 +1,96 @@
+use k8s_openapi::api::core::v1::ResourceRequirements;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+use crate::crd::Role;
+
+const MB: u64 = 1024 * 1024;
+
+/// Resources of the Postgres container for a role
+pub fn requirements(role: Option<&Role>) -> Option<ResourceRequirements> {
+    role.and_then(|r| r.resources.clone())
+}
+
+/// Postgres memory settings sized to the container's memory
+///
+/// `shared_buffers` gets a quarter of the memory limit, or of the request when there is no
+/// limit, and `work_mem` a 256th of it, but never less than the Postgres default of 4MB.
+/// Nothing is returned when no memory is set, leaving the defaults of the image in place.
+pub fn memory_settings(role: Option<&Role>) -> Vec<(&'static str, String)> {
+    let memory = role
+        .and_then(|r| r.resources.as_ref())
+        .and_then(|resources| {
+            [&resources.limits, &resources.requests]
+                .into_iter()
+                .flatten()
+                .find_map(|quantities| quantities.get("memory"))
+        })
+        .and_then(bytes);
+    let Some(memory) = memory else {
+        return vec![];
+    };
+
+    let shared_buffers = memory / 4 / MB;
+    let work_mem = (memory / 256 / MB).max(4);
+    vec![
+        ("shared_buffers", format!("{shared_buffers}MB")),
+        ("work_mem", format!("{work_mem}MB")),
+    ]
+}
+
+/// Number of bytes in a quantity such as `512Mi` or `2G`
+pub(crate) fn bytes(quantity: &Quantity) -> Option<u64> {
+    let quantity = quantity.0.trim();
+    let (number, suffix) = quantity.split_at(
+        quantity
+            .find(|c: char| c.is_ascii_alphabetic())
+            .unwrap_or(quantity.len()),
+    );
+    let multiplier: f64 = match suffix {
+        "" => 1.0,
+        "k" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        "T" => 1e12,
+        "Ki" => 1024.0,
+        "Mi" => 1024.0 * 1024.0,
+        "Gi" => 1024.0 * 1024.0 * 1024.0,
+        "Ti" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    let number: f64 = number.parse().ok()?;
+    Some((number * multiplier) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes_of(quantity: &str) -> Option<u64> {
+        bytes(&Quantity(quantity.to_owned()))
+    }
+
+    #[test]
+    fn binary_suffixes() {
+        assert_eq!(bytes_of("512Mi"), Some(512 * 1024 * 1024));
+        assert_eq!(bytes_of("2Gi"), Some(2 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn decimal_suffixes() {
+        assert_eq!(bytes_of("2G"), Some(2_000_000_000));
+        assert_eq!(bytes_of("1500"), Some(1500));
+    }
+
+    #[test]
+    fn fractions() {
+        assert_eq!(bytes_of("1.5Gi"), Some(1536 * 1024 * 1024));
+        assert_eq!(bytes_of("0.5G"), Some(500_000_000));
+    }
+
+    #[test]
+    fn unsupported() {
+        assert_eq!(bytes_of("100m"), None);
+        assert_eq!(bytes_of("1e3"), None);
+        assert_eq!(bytes_of("Gi"), None);
+    }
+}
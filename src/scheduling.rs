@@ -0,0 +1,48 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::core::v1::{
+    Affinity, PodAffinityTerm, PodAntiAffinity, PodSpec, WeightedPodAffinityTerm,
+};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+
+use crate::crd::Role;
+
+/// A pod spec holding only the scheduling constraints of `role`
+///
+/// `affinity` is used when the role sets none of its own.
+pub fn pod_spec(role: Option<&Role>, affinity: Option<Affinity>) -> PodSpec {
+    let role = role.cloned().unwrap_or_default();
+    PodSpec {
+        node_selector: role.node_selector,
+        tolerations: role.tolerations,
+        affinity: role.affinity.or(affinity),
+        topology_spread_constraints: role.topology_spread_constraints,
+        ..PodSpec::default()
+    }
+}
+
+/// Prefer placing the pods matching `labels` on different nodes, and then in different zones
+pub fn spread(labels: BTreeMap<String, String>) -> Affinity {
+    let term = |weight: i32, topology_key: &str| WeightedPodAffinityTerm {
+        weight,
+        pod_affinity_term: PodAffinityTerm {
+            label_selector: Some(LabelSelector {
+                match_expressions: None,
+                match_labels: Some(labels.clone()),
+            }),
+            topology_key: topology_key.to_owned(),
+            ..PodAffinityTerm::default()
+        },
+    };
+
+    Affinity {
+        pod_anti_affinity: Some(PodAntiAffinity {
+            preferred_during_scheduling_ignored_during_execution: Some(vec![
+                term(100, "kubernetes.io/hostname"),
+                term(50, "topology.kubernetes.io/zone"),
+            ]),
+            ..PodAntiAffinity::default()
+        }),
+        ..Affinity::default()
+    }
+}
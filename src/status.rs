@@ -0,0 +1,446 @@
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
+use k8s_openapi::chrono::Utc;
+use kube::api::{Patch, PatchParams};
+use kube::{Api, Client, Error, Resource};
+use serde_json::{json, Value};
+
+use crate::cluster::CitusDeployment;
+use crate::crd::{CitusCluster, CitusClusterStatus, ClusterPhase};
+use crate::{master, workers};
+
+/// Derive the status of a cluster from the children produced by the last reconcile
+pub fn observe(cc: &CitusCluster, deployment: &CitusDeployment) -> CitusClusterStatus {
+    let name = cc.meta().name.clone().unwrap_or_default();
+    let namespace = cc.meta().namespace.clone().unwrap_or_default();
+    let desired = cc.spec.workers;
+    let replicas = workers::replicas(&deployment.workers);
+    let ready_workers = workers::ready_replicas(&deployment.workers);
+    let registered_workers = workers::registered(&deployment.workers);
+    let master_ready = master::ready(&deployment.master);
+    let bootstrapped = cc
+        .status
+        .as_ref()
+        .is_some_and(|s| !matches!(s.phase, ClusterPhase::Pending | ClusterPhase::Bootstrapping));
+
+    // Each stage of bringing the cluster up only counts once the ones before it have passed
+    let workers_ready = master_ready && replicas == desired && ready_workers >= desired;
+    let workers_registered = workers_ready && registered_workers == desired;
+    let metadata_synced = workers_registered && deployment.metadata_synced;
+    let stages = [
+        (
+            "CoordinatorReady",
+            master_ready,
+            if master_ready {
+                "Coordinator is ready".to_owned()
+            } else {
+                "Waiting for the coordinator to be ready".to_owned()
+            },
+        ),
+        (
+            "WorkersReady",
+            workers_ready,
+            format!("{ready_workers}/{desired} workers ready"),
+        ),
+        (
+            "WorkersRegistered",
+            workers_registered,
+            format!("{registered_workers}/{desired} workers registered"),
+        ),
+        (
+            "MetadataSynced",
+            metadata_synced,
+            if metadata_synced {
+                "Citus metadata is synced to every worker".to_owned()
+            } else {
+                "Waiting for the Citus metadata to be synced to every worker".to_owned()
+            },
+        ),
+    ];
+    let waiting_on = stages
+        .iter()
+        .find(|(_, passed, _)| !passed)
+        .map(|(_, _, message)| message.clone());
+
+    let (phase, message) = if let Some(reason) = &deployment.degraded {
+        (ClusterPhase::Degraded, reason.clone())
+    } else if let Some(failure) = deployment
+        .major_upgrade
+        .as_ref()
+        .and_then(|u| u.failure.clone())
+    {
+        (ClusterPhase::Degraded, failure)
+    } else if let Some(upgrade) = &deployment.major_upgrade {
+        (
+            ClusterPhase::Upgrading,
+            format!("Upgrading to {}: {:?}", upgrade.image, upgrade.step),
+        )
+    } else if let Some(failure) = deployment.upgrade.as_ref().and_then(|u| u.failure.clone()) {
+        (ClusterPhase::Degraded, failure)
+    } else if let Some(upgrade) = &deployment.upgrade {
+        (
+            ClusterPhase::Upgrading,
+            format!("Upgrading {} to {}", upgrade.node, upgrade.image),
+        )
+    } else if let Some(resize) = deployment.volume_resizes.first() {
+        let mut message = format!(
+            "Growing {} volumes to {}: {} claims pending",
+            resize.stateful_set,
+            resize.size.0,
+            resize.pending_claims.len()
+        );
+        for (claim, condition) in &resize.claim_conditions {
+            message.push_str(&format!("; {claim}: {condition}"));
+        }
+        (ClusterPhase::Resizing, message)
+    } else if metadata_synced {
+        (
+            ClusterPhase::Ready,
+            format!("{registered_workers} workers registered"),
+        )
+    } else if !bootstrapped && !master_ready && ready_workers == 0 {
+        (
+            ClusterPhase::Pending,
+            "Waiting for coordinator and worker pods".to_owned(),
+        )
+    } else if !bootstrapped {
+        (ClusterPhase::Bootstrapping, waiting_on.unwrap_or_default())
+    } else if replicas != desired || registered_workers != replicas {
+        (
+            ClusterPhase::Scaling,
+            format!("Scaling from {registered_workers} to {desired} workers"),
+        )
+    } else {
+        (ClusterPhase::Degraded, waiting_on.unwrap_or_default())
+    };
+
+    let previous = cc
+        .status
+        .as_ref()
+        .map(|s| s.conditions.clone())
+        .unwrap_or_default();
+    let generation = cc.meta().generation;
+    let stage_conditions: Vec<Condition> = stages
+        .into_iter()
+        .map(|(type_, passed, message)| {
+            let reason = if passed { "Passed" } else { "Waiting" };
+            condition(&previous, type_, passed, reason, &message, generation)
+        })
+        .collect();
+
+    with_phase(cc, phase, &message, |status| CitusClusterStatus {
+        conditions: [status.conditions, stage_conditions].concat(),
+        ready_workers,
+        registered_workers,
+        coordinator_endpoint: Some(master::endpoint(&name, &namespace)),
+        upgrade: deployment.upgrade.clone(),
+        major_upgrade: deployment.major_upgrade.clone(),
+        volume_resizes: deployment.volume_resizes.clone(),
+        ..status
+    })
+}
+
+/// Whether a cluster has converged, so it only needs another pass once something changes
+///
+/// A cluster that is still converging may be waiting on state that is not watched, like the
+/// capacity of its volumes or the Citus metadata, so it has to be checked again.
+pub fn settled(status: &CitusClusterStatus) -> bool {
+    status.phase == ClusterPhase::Ready
+        && status.upgrade.is_none()
+        && status.major_upgrade.is_none()
+        && status.volume_resizes.is_empty()
+}
+
+/// Status for a cluster whose children are being torn down
+///
+/// `pending` lists the children that could not be deleted on this pass.
+pub fn deleting(cc: &CitusCluster, pending: Vec<String>) -> CitusClusterStatus {
+    let message = if pending.is_empty() {
+        "Deleting cluster".to_owned()
+    } else {
+        format!("Waiting to delete {}", pending.join(", "))
+    };
+    with_phase(cc, ClusterPhase::Deleting, &message, |status| {
+        CitusClusterStatus {
+            pending_deletion: pending,
+            ..status
+        }
+    })
+}
+
+pub async fn patch(
+    client: Client,
+    name: &str,
+    status: &CitusClusterStatus,
+    namespace: &str,
+) -> Result<CitusCluster, Error> {
+    let api: Api<CitusCluster> = Api::namespaced(client, namespace);
+    let status: Value = json!({ "status": status });
+    let patch: Patch<&Value> = Patch::Merge(&status);
+    api.patch_status(name, &PatchParams::default(), &patch)
+        .await
+}
+
+/// Start from the previous status, set the phase and its conditions, then apply `f`
+fn with_phase(
+    cc: &CitusCluster,
+    phase: ClusterPhase,
+    message: &str,
+    f: impl FnOnce(CitusClusterStatus) -> CitusClusterStatus,
+) -> CitusClusterStatus {
+    let previous = cc.status.clone().unwrap_or_default();
+    let generation = cc.meta().generation;
+    let reason = format!("{phase:?}");
+    let conditions = [
+        ("Ready", phase == ClusterPhase::Ready),
+        (
+            "Progressing",
+            matches!(
+                phase,
+                ClusterPhase::Bootstrapping
+                    | ClusterPhase::Scaling
+                    | ClusterPhase::Resizing
+                    | ClusterPhase::Upgrading
+                    | ClusterPhase::Deleting
+            ),
+        ),
+        ("Degraded", phase == ClusterPhase::Degraded),
+    ]
+    .into_iter()
+    .map(|(type_, is_true)| {
+        condition(
+            &previous.conditions,
+            type_,
+            is_true,
+            &reason,
+            message,
+            generation,
+        )
+    })
+    .collect();
+
+    f(CitusClusterStatus {
+        phase,
+        conditions,
+        observed_generation: generation,
+        ..previous
+    })
+}
+
+/// Build a condition, keeping its transition time if its status has not changed
+fn condition(
+    previous: &[Condition],
+    type_: &str,
+    is_true: bool,
+    reason: &str,
+    message: &str,
+    generation: Option<i64>,
+) -> Condition {
+    let status = if is_true { "True" } else { "False" };
+    let last_transition_time = previous
+        .iter()
+        .find(|c| c.type_ == type_ && c.status == status)
+        .map(|c| c.last_transition_time.clone())
+        .unwrap_or_else(|| Time(Utc::now()));
+
+    Condition {
+        type_: type_.to_owned(),
+        status: status.to_owned(),
+        reason: reason.to_owned(),
+        message: message.to_owned(),
+        observed_generation: generation,
+        last_transition_time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use k8s_openapi::api::apps::v1::{StatefulSet, StatefulSetSpec, StatefulSetStatus};
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use serde_json::json;
+
+    use super::*;
+    use crate::crd::VolumeResize;
+
+    fn cluster(workers: i32, phase: Option<ClusterPhase>) -> CitusCluster {
+        let spec = serde_json::from_value(json!({ "workers": workers, "worker_storage": 1 }))
+            .expect("spec");
+        let mut cc = CitusCluster::new("citus", spec);
+        cc.metadata.namespace = Some("default".to_owned());
+        cc.status = phase.map(|phase| CitusClusterStatus {
+            phase,
+            ..CitusClusterStatus::default()
+        });
+        cc
+    }
+
+    fn stateful_set(replicas: i32, ready: i32, registered: Option<i32>) -> StatefulSet {
+        StatefulSet {
+            metadata: ObjectMeta {
+                annotations: registered.map(|registered| {
+                    BTreeMap::from([(
+                        "citusclusters.jw3.xyz/registered-workers".to_owned(),
+                        registered.to_string(),
+                    )])
+                }),
+                ..ObjectMeta::default()
+            },
+            spec: Some(StatefulSetSpec {
+                replicas: Some(replicas),
+                ..StatefulSetSpec::default()
+            }),
+            status: Some(StatefulSetStatus {
+                ready_replicas: Some(ready),
+                ..StatefulSetStatus::default()
+            }),
+        }
+    }
+
+    fn deployment(master_ready: bool, workers: StatefulSet, synced: bool) -> CitusDeployment {
+        CitusDeployment {
+            master: stateful_set(1, i32::from(master_ready), None),
+            workers,
+            degraded: None,
+            upgrade: None,
+            major_upgrade: None,
+            metadata_synced: synced,
+            volume_resizes: vec![],
+        }
+    }
+
+    fn condition<'a>(status: &'a CitusClusterStatus, type_: &str) -> &'a Condition {
+        status
+            .conditions
+            .iter()
+            .find(|c| c.type_ == type_)
+            .expect("condition")
+    }
+
+    #[test]
+    fn pending_until_any_pod_is_ready() {
+        let status = observe(
+            &cluster(2, None),
+            &deployment(false, stateful_set(2, 0, Some(0)), false),
+        );
+        assert_eq!(status.phase, ClusterPhase::Pending);
+        assert_eq!(condition(&status, "CoordinatorReady").status, "False");
+    }
+
+    #[test]
+    fn bootstrapping_reports_the_first_stage_not_passed() {
+        let cc = cluster(2, Some(ClusterPhase::Bootstrapping));
+
+        let status = observe(&cc, &deployment(false, stateful_set(2, 2, Some(0)), false));
+        assert_eq!(status.phase, ClusterPhase::Bootstrapping);
+        assert_eq!(
+            condition(&status, "Progressing").message,
+            "Waiting for the coordinator to be ready"
+        );
+        // Workers only count as ready once the coordinator is
+        assert_eq!(condition(&status, "WorkersReady").status, "False");
+
+        let status = observe(&cc, &deployment(true, stateful_set(2, 1, Some(0)), false));
+        assert_eq!(
+            condition(&status, "Progressing").message,
+            "1/2 workers ready"
+        );
+
+        let status = observe(&cc, &deployment(true, stateful_set(2, 2, Some(0)), false));
+        assert_eq!(condition(&status, "WorkersReady").status, "True");
+        assert_eq!(condition(&status, "WorkersRegistered").status, "False");
+        assert_eq!(
+            condition(&status, "Progressing").message,
+            "0/2 workers registered"
+        );
+
+        let status = observe(&cc, &deployment(true, stateful_set(2, 2, Some(2)), false));
+        assert_eq!(status.phase, ClusterPhase::Bootstrapping);
+        assert_eq!(condition(&status, "MetadataSynced").status, "False");
+    }
+
+    #[test]
+    fn ready_once_the_metadata_is_synced() {
+        let status = observe(
+            &cluster(2, Some(ClusterPhase::Bootstrapping)),
+            &deployment(true, stateful_set(2, 2, Some(2)), true),
+        );
+        assert_eq!(status.phase, ClusterPhase::Ready);
+        for type_ in [
+            "Ready",
+            "CoordinatorReady",
+            "WorkersReady",
+            "WorkersRegistered",
+        ] {
+            assert_eq!(condition(&status, type_).status, "True", "{type_}");
+        }
+        assert_eq!(condition(&status, "Progressing").status, "False");
+        assert!(settled(&status));
+    }
+
+    #[test]
+    fn degraded_reason_wins_over_progress() {
+        let mut deployment = deployment(true, stateful_set(2, 2, Some(2)), true);
+        deployment.degraded = Some("Registering workers failed".to_owned());
+        let status = observe(&cluster(2, Some(ClusterPhase::Ready)), &deployment);
+        assert_eq!(status.phase, ClusterPhase::Degraded);
+        assert_eq!(
+            condition(&status, "Degraded").message,
+            "Registering workers failed"
+        );
+        assert!(!settled(&status));
+    }
+
+    #[test]
+    fn degraded_when_a_bootstrapped_cluster_loses_a_stage() {
+        let status = observe(
+            &cluster(2, Some(ClusterPhase::Ready)),
+            &deployment(false, stateful_set(2, 2, Some(2)), false),
+        );
+        assert_eq!(status.phase, ClusterPhase::Degraded);
+        assert_eq!(
+            condition(&status, "Degraded").message,
+            "Waiting for the coordinator to be ready"
+        );
+    }
+
+    #[test]
+    fn scaling_while_workers_are_added() {
+        let status = observe(
+            &cluster(3, Some(ClusterPhase::Ready)),
+            &deployment(true, stateful_set(3, 2, Some(2)), false),
+        );
+        assert_eq!(status.phase, ClusterPhase::Scaling);
+        assert_eq!(
+            condition(&status, "Progressing").message,
+            "Scaling from 2 to 3 workers"
+        );
+        assert_eq!(status.registered_workers, 2);
+    }
+
+    #[test]
+    fn resizing_while_claims_are_pending() {
+        let mut deployment = deployment(true, stateful_set(2, 2, Some(2)), true);
+        deployment.volume_resizes = vec![VolumeResize {
+            stateful_set: "citus-workers".to_owned(),
+            size: Quantity("2Gi".to_owned()),
+            replicas: 2,
+            annotations: BTreeMap::new(),
+            pending_claims: vec!["citus-citus-workers-1".to_owned()],
+            claim_conditions: BTreeMap::from([(
+                "citus-citus-workers-1".to_owned(),
+                "FileSystemResizePending".to_owned(),
+            )]),
+        }];
+        let status = observe(&cluster(2, Some(ClusterPhase::Ready)), &deployment);
+        assert_eq!(status.phase, ClusterPhase::Resizing);
+        assert_eq!(
+            condition(&status, "Progressing").message,
+            "Growing citus-workers volumes to 2Gi: 1 claims pending; \
+             citus-citus-workers-1: FileSystemResizePending"
+        );
+        assert_eq!(status.volume_resizes.len(), 1);
+        assert!(!settled(&status));
+    }
+}
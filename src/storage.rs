@@ -1,29 +1,137 @@
 use std::collections::BTreeMap;
 
-use futures::TryFutureExt;
+use k8s_openapi::api::apps::v1::{StatefulSet, StatefulSetPersistentVolumeClaimRetentionPolicy};
 use k8s_openapi::api::core::v1::{
-    PersistentVolumeClaim, PersistentVolumeClaimSpec, ResourceRequirements,
+    EnvVar, PersistentVolumeClaim, PersistentVolumeClaimSpec, ResourceRequirements, VolumeMount,
 };
 use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::api::{DeleteParams, ListParams};
 use kube::{Api, Client, Error};
 
-pub async fn delete_storage(client: Client, name: &str, namespace: &str) -> Result<(), Error> {
+use crate::crd::{CitusClusterSpec, RetentionPolicy};
+use crate::{resources, workers};
+
+/// Where the data volume is mounted
+pub const DATA_DIR: &str = "/var/lib/postgresql/data";
+
+/// Directory on its data volume that the coordinator keeps its data directory in
+///
+/// initdb refuses a directory that is not empty, such as the root of a volume holding
+/// `lost+found`. The workers keep theirs at the root of the volume, where existing clusters
+/// were initialised.
+pub const COORDINATOR_PGDATA: &str = "/var/lib/postgresql/data/pgdata";
+
+/// Where the WAL volume is mounted
+pub const WAL_DIR: &str = "/var/lib/postgresql/wal";
+
+/// Directory on the WAL volume that initdb puts the write-ahead log in
+///
+/// initdb wants an empty directory, which the root of a fresh volume may not be.
+pub const WAL_PATH: &str = "/var/lib/postgresql/wal/pg_wal";
+
+/// Delete every volume claimed by the coordinator and the workers of a cluster, returning the
+/// names of the claims deleted
+///
+/// StatefulSets label their claims with their selector, so the claims of every ordinal are
+/// found by the `app` label of the cluster. As other claims may carry the same label, only
+/// those named after the claim templates of the cluster are deleted.
+pub async fn delete_storage(
+    client: Client,
+    name: &str,
+    namespace: &str,
+) -> Result<Vec<String>, Error> {
     let api: Api<PersistentVolumeClaim> = Api::namespaced(client.clone(), namespace);
-    api.delete(name, &Default::default()).map_ok(|_| ()).await
+    let claims = api
+        .list(&ListParams::default().labels(&format!("app={name}")))
+        .await?;
+
+    let mut deleted = vec![];
+    for claim in claims.items {
+        let claim_name = claim.metadata.name.unwrap_or_default();
+        if !owned(name, &claim_name) {
+            continue;
+        }
+        api.delete(&claim_name, &DeleteParams::default()).await?;
+        deleted.push(claim_name);
+    }
+    Ok(deleted)
+}
+
+/// Whether `claim` was made from a claim template of the cluster `name`
+fn owned(name: &str, claim: &str) -> bool {
+    [name.to_owned(), wal_qname(name)].iter().any(|template| {
+        [name.to_owned(), workers::qname(name)]
+            .iter()
+            .any(|ss_name| {
+                claim
+                    .strip_prefix(&format!("{template}-{ss_name}-"))
+                    .is_some_and(|ordinal| ordinal.parse::<i32>().is_ok())
+            })
+    })
+}
+
+/// How the volumes of a StatefulSet are kept, following `spec.storage.retentionPolicy`
+///
+/// Volumes are always kept when a StatefulSet scales in, since a major upgrade stops the
+/// cluster by scaling it to zero.
+pub fn retention_policy(
+    spec: &CitusClusterSpec,
+) -> StatefulSetPersistentVolumeClaimRetentionPolicy {
+    StatefulSetPersistentVolumeClaimRetentionPolicy {
+        when_deleted: Some(format!("{:?}", retention(spec))),
+        when_scaled: Some(format!("{:?}", RetentionPolicy::Retain)),
+    }
 }
 
-pub fn volume_claim_template(name: &str, gi: usize) -> PersistentVolumeClaim {
+/// What happens to the volumes of a cluster when it is deleted
+pub fn retention(spec: &CitusClusterSpec) -> RetentionPolicy {
+    spec.storage
+        .as_ref()
+        .map(|s| s.retention_policy)
+        .unwrap_or_default()
+}
+
+/// Size of each coordinator volume: `coordinatorStorage`, then `storage.size`, then
+/// `worker_storage`
+pub fn coordinator_size(spec: &CitusClusterSpec) -> Quantity {
+    spec.coordinator_storage
+        .clone()
+        .unwrap_or_else(|| worker_size(spec))
+}
+
+/// Size of each worker volume: `storage.size`, then `worker_storage`
+pub fn worker_size(spec: &CitusClusterSpec) -> Quantity {
+    spec.storage
+        .as_ref()
+        .and_then(|s| s.size.clone())
+        .unwrap_or_else(|| Quantity(format!("{}Gi", spec.worker_storage)))
+}
+
+pub fn volume_claim_template(
+    name: &str,
+    size: Quantity,
+    spec: &CitusClusterSpec,
+) -> PersistentVolumeClaim {
     let mut worker_labels: BTreeMap<String, Quantity> = BTreeMap::new();
-    worker_labels.insert("storage".to_owned(), Quantity(format!("{gi}Gi")));
+    worker_labels.insert("storage".to_owned(), size);
 
+    let storage = spec.storage.clone().unwrap_or_default();
     PersistentVolumeClaim {
         metadata: ObjectMeta {
             name: Some(name.to_owned()),
+            annotations: storage.annotations,
             ..Default::default()
         },
         spec: Some(PersistentVolumeClaimSpec {
-            access_modes: Some(vec!["ReadWriteOnce".to_owned()]),
+            access_modes: Some(
+                storage
+                    .access_modes
+                    .unwrap_or_else(|| vec!["ReadWriteOnce".to_owned()]),
+            ),
+            storage_class_name: storage.storage_class_name,
+            volume_mode: storage.volume_mode,
+            selector: storage.selector,
             resources: Some(ResourceRequirements {
                 requests: Some(worker_labels),
                 ..Default::default()
@@ -33,3 +141,295 @@ pub fn volume_claim_template(name: &str, gi: usize) -> PersistentVolumeClaim {
         ..Default::default()
     }
 }
+
+/// The claim template to apply a StatefulSet with, and why it differs from `desired`
+///
+/// Claim templates cannot change once a StatefulSet exists, so a live StatefulSet keeps its own.
+/// Size changes are left to [`crate::resize`]. `role` names the pods in the reason.
+pub fn claim_template(
+    live: Option<&StatefulSet>,
+    desired: PersistentVolumeClaim,
+    role: &str,
+) -> (PersistentVolumeClaim, Option<String>) {
+    let Some(live) = live.and_then(live_claim) else {
+        return (desired, None);
+    };
+
+    let live_spec = live.spec.clone().unwrap_or_default();
+    let desired_spec = desired.spec.clone().unwrap_or_default();
+    let reason = if live_spec.storage_class_name != desired_spec.storage_class_name
+        || live_spec.access_modes != desired_spec.access_modes
+        || volume_mode(&live_spec) != volume_mode(&desired_spec)
+        || live_spec.selector != desired_spec.selector
+        || live.metadata.annotations != desired.metadata.annotations
+    {
+        Some(format!(
+            "{role} storage class, access modes, volume mode, selector and annotations cannot be \
+             changed on a running cluster"
+        ))
+    } else {
+        None
+    };
+
+    (live, reason)
+}
+
+/// The claim template for the WAL volume of each node, if `spec.storage.wal` asks for one
+pub fn wal_claim_template(name: &str, spec: &CitusClusterSpec) -> Option<PersistentVolumeClaim> {
+    let wal = spec.storage.as_ref()?.wal.clone()?;
+    Some(PersistentVolumeClaim {
+        metadata: ObjectMeta {
+            name: Some(wal_qname(name)),
+            annotations: wal.annotations,
+            ..Default::default()
+        },
+        spec: Some(PersistentVolumeClaimSpec {
+            access_modes: Some(
+                wal.access_modes
+                    .unwrap_or_else(|| vec!["ReadWriteOnce".to_owned()]),
+            ),
+            storage_class_name: wal.storage_class_name,
+            resources: Some(ResourceRequirements {
+                requests: Some(BTreeMap::from([("storage".to_owned(), wal.size)])),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+/// The WAL claim template to apply a StatefulSet with, and why it differs from `desired`
+///
+/// As with [`claim_template`], a live StatefulSet keeps the WAL volume it was created with, or
+/// the lack of one.
+pub fn wal_template(
+    name: &str,
+    live: Option<&StatefulSet>,
+    desired: Option<PersistentVolumeClaim>,
+    role: &str,
+) -> (Option<PersistentVolumeClaim>, Option<String>) {
+    let Some(live) = live else {
+        return (desired, None);
+    };
+
+    let live = live_claims(live)
+        .into_iter()
+        .find(|claim| claim.metadata.name.as_deref() == Some(wal_qname(name).as_str()));
+    let reason = match (&live, &desired) {
+        (None, None) => None,
+        (Some(_), None) | (None, Some(_)) => Some(format!(
+            "{role} WAL volume cannot be added or removed on a running cluster"
+        )),
+        (Some(live), Some(desired)) => {
+            let live_spec = live.spec.clone().unwrap_or_default();
+            let desired_spec = desired.spec.clone().unwrap_or_default();
+            (size(live).as_ref().and_then(resources::bytes)
+                != size(desired).as_ref().and_then(resources::bytes)
+                || live_spec.storage_class_name != desired_spec.storage_class_name
+                || live_spec.access_modes != desired_spec.access_modes
+                || live.metadata.annotations != desired.metadata.annotations)
+                .then(|| format!("{role} WAL volume cannot be changed on a running cluster"))
+        }
+    };
+
+    (live, reason)
+}
+
+/// Whether `claims` include a WAL volume
+pub fn has_wal(name: &str, claims: &[PersistentVolumeClaim]) -> bool {
+    claims
+        .iter()
+        .any(|claim| claim.metadata.name.as_deref() == Some(wal_qname(name).as_str()))
+}
+
+/// Mounts the WAL volume of a node at [`WAL_DIR`]
+pub fn wal_volume_mount(name: &str) -> VolumeMount {
+    VolumeMount {
+        mount_path: WAL_DIR.to_owned(),
+        name: wal_qname(name),
+        ..Default::default()
+    }
+}
+
+/// Points the coordinator's Postgres at [`COORDINATOR_PGDATA`]
+pub fn coordinator_data_env() -> EnvVar {
+    EnvVar {
+        name: "PGDATA".to_owned(),
+        value: Some(COORDINATOR_PGDATA.to_owned()),
+        ..EnvVar::default()
+    }
+}
+
+/// The data directory of worker `ordinal`, or of the coordinator when it is `None`
+pub fn data_directory(ordinal: Option<i32>) -> &'static str {
+    match ordinal {
+        Some(_) => DATA_DIR,
+        None => COORDINATOR_PGDATA,
+    }
+}
+
+/// Has the image entrypoint pass [`WAL_PATH`] to initdb as `--waldir`
+///
+/// Only a fresh data directory is initialised, so this has no effect on existing nodes.
+pub fn wal_env() -> EnvVar {
+    EnvVar {
+        name: "POSTGRES_INITDB_WALDIR".to_owned(),
+        value: Some(WAL_PATH.to_owned()),
+        ..EnvVar::default()
+    }
+}
+
+/// The volume mode of a claim, which the API server defaults to Filesystem
+fn volume_mode(spec: &PersistentVolumeClaimSpec) -> &str {
+    spec.volume_mode.as_deref().unwrap_or("Filesystem")
+}
+
+/// The claim template a StatefulSet was created with
+pub fn live_claim(ss: &StatefulSet) -> Option<PersistentVolumeClaim> {
+    live_claims(ss).into_iter().next()
+}
+
+/// Every claim template a StatefulSet was created with, the data volume first
+pub fn live_claims(ss: &StatefulSet) -> Vec<PersistentVolumeClaim> {
+    ss.spec
+        .as_ref()
+        .and_then(|s| s.volume_claim_templates.clone())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|claim| PersistentVolumeClaim {
+            status: None,
+            ..claim
+        })
+        .collect()
+}
+
+/// Name of the claim made from [`volume_claim_template`] `name` for ordinal `i` of the
+/// StatefulSet `ss_name`
+pub(crate) fn claim_name(name: &str, ss_name: &str, i: i32) -> String {
+    format!("{name}-{ss_name}-{i}")
+}
+
+pub(crate) fn wal_qname(name: &str) -> String {
+    format!("{name}-wal")
+}
+
+/// Size requested by a claim
+pub fn size(pvc: &PersistentVolumeClaim) -> Option<Quantity> {
+    pvc.spec
+        .as_ref()
+        .and_then(|s| s.resources.as_ref())
+        .and_then(|r| r.requests.as_ref())
+        .and_then(|requests| requests.get("storage"))
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::apps::v1::StatefulSetSpec;
+    use serde_json::json;
+
+    use super::*;
+
+    fn spec(storage: serde_json::Value) -> CitusClusterSpec {
+        serde_json::from_value(json!({ "workers": 1, "worker_storage": 1, "storage": storage }))
+            .expect("spec")
+    }
+
+    fn stateful_set(claim: PersistentVolumeClaim) -> StatefulSet {
+        StatefulSet {
+            spec: Some(StatefulSetSpec {
+                volume_claim_templates: Some(vec![claim]),
+                ..StatefulSetSpec::default()
+            }),
+            ..StatefulSet::default()
+        }
+    }
+
+    #[test]
+    fn volumes_are_retained_by_default() {
+        let policy = retention_policy(&spec(json!({})));
+        assert_eq!(policy.when_deleted.as_deref(), Some("Retain"));
+        assert_eq!(policy.when_scaled.as_deref(), Some("Retain"));
+    }
+
+    #[test]
+    fn delete_policy_only_applies_when_the_cluster_is_deleted() {
+        let policy = retention_policy(&spec(json!({ "retentionPolicy": "Delete" })));
+        assert_eq!(policy.when_deleted.as_deref(), Some("Delete"));
+        // A major upgrade scales the cluster to zero, which must keep the volumes
+        assert_eq!(policy.when_scaled.as_deref(), Some("Retain"));
+    }
+
+    #[test]
+    fn coordinator_size_defaults_to_the_worker_size() {
+        let mut spec = spec(json!({ "size": "5Gi" }));
+        assert_eq!(coordinator_size(&spec), Quantity("5Gi".to_owned()));
+        spec.coordinator_storage = Some(Quantity("500Mi".to_owned()));
+        assert_eq!(coordinator_size(&spec), Quantity("500Mi".to_owned()));
+        assert_eq!(worker_size(&spec), Quantity("5Gi".to_owned()));
+    }
+
+    #[test]
+    fn owned_claims() {
+        assert!(owned("citus", "citus-citus-0"));
+        assert!(owned("citus", "citus-citus-workers-3"));
+        assert!(owned("citus", "citus-wal-citus-0"));
+        assert!(owned("citus", "citus-wal-citus-workers-3"));
+        assert!(!owned("citus", "data-citus-0"));
+        assert!(!owned("citus", "citus-citus-backup"));
+        assert!(!owned("citus", "citus-citus-workers-3-old"));
+    }
+
+    #[test]
+    fn new_stateful_set_takes_the_desired_template() {
+        let desired = volume_claim_template("citus", Quantity("1Gi".to_owned()), &spec(json!({})));
+        let (claim, reason) = claim_template(None, desired.clone(), "Worker");
+        assert_eq!(claim, desired);
+        assert_eq!(reason, None);
+    }
+
+    #[test]
+    fn size_is_left_to_resize() {
+        let spec = spec(json!({}));
+        let live = volume_claim_template("citus", Quantity("1Gi".to_owned()), &spec);
+        let desired = volume_claim_template("citus", Quantity("2Gi".to_owned()), &spec);
+        let (claim, reason) = claim_template(Some(&stateful_set(live.clone())), desired, "Worker");
+        assert_eq!(claim, live);
+        assert_eq!(reason, None);
+    }
+
+    #[test]
+    fn defaulted_volume_mode_is_not_a_change() {
+        let desired = volume_claim_template("citus", Quantity("1Gi".to_owned()), &spec(json!({})));
+        let mut live = desired.clone();
+        live.spec.as_mut().unwrap().volume_mode = Some("Filesystem".to_owned());
+        let (_, reason) = claim_template(Some(&stateful_set(live.clone())), desired, "Worker");
+        assert_eq!(reason, None);
+
+        let desired = volume_claim_template(
+            "citus",
+            Quantity("1Gi".to_owned()),
+            &spec(json!({ "volumeMode": "Block" })),
+        );
+        let (_, reason) = claim_template(Some(&stateful_set(live)), desired, "Worker");
+        assert!(reason.is_some());
+    }
+
+    #[test]
+    fn live_template_is_kept_when_it_cannot_change() {
+        let live = volume_claim_template(
+            "citus",
+            Quantity("1Gi".to_owned()),
+            &spec(json!({ "storageClassName": "standard" })),
+        );
+        let desired = volume_claim_template(
+            "citus",
+            Quantity("1Gi".to_owned()),
+            &spec(json!({ "storageClassName": "fast" })),
+        );
+        let (claim, reason) = claim_template(Some(&stateful_set(live.clone())), desired, "Worker");
+        assert_eq!(claim, live);
+        assert!(reason.is_some_and(|r| r.starts_with("Worker storage class")));
+    }
+}
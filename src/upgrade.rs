@@ -0,0 +1,256 @@
+use k8s_openapi::api::apps::v1::StatefulSet;
+use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+use kube::api::{Patch, PatchParams};
+use kube::{Api, Client, Error};
+use serde_json::{json, Value};
+
+use crate::crd::{CitusClusterSpec, UpgradeStatus};
+use crate::{jobs, master, workers};
+
+const PENDING_ANNOTATION: &str = "citusclusters.jw3.xyz/citus-update";
+
+/// A node taking part in a rolling upgrade
+struct Node {
+    /// Short identifier used to name the node's Jobs
+    id: String,
+    /// Pod running the node
+    pod: String,
+    /// Address the node's extension is updated through
+    host: String,
+}
+
+enum Step {
+    Waiting,
+    Failed(String),
+    Done,
+}
+
+/// Image run by the pods of a StatefulSet
+pub fn image(ss: &StatefulSet) -> Option<&str> {
+    ss.spec
+        .as_ref()
+        .and_then(|s| s.template.spec.as_ref())
+        .and_then(|s| s.containers.first())
+        .and_then(|c| c.image.as_deref())
+}
+
+/// Hold back the StatefulSet `ss_name` at `partition` and mark it as needing an extension update
+///
+/// This must happen before the new image is applied, so only ordinals from `partition` up roll
+/// onto it straight away.
+pub async fn begin(
+    client: Client,
+    ss_name: &str,
+    partition: i32,
+    namespace: &str,
+) -> Result<StatefulSet, Error> {
+    let ss_api: Api<StatefulSet> = Api::namespaced(client, namespace);
+    let pending: Value = json!({
+        "metadata": {
+            "annotations": {
+                PENDING_ANNOTATION: "true"
+            }
+        },
+        "spec": {
+            "updateStrategy": {
+                "type": "RollingUpdate",
+                "rollingUpdate": {
+                    "partition": partition
+                }
+            }
+        }
+    });
+    let patch: Patch<&Value> = Patch::Merge(&pending);
+    ss_api.patch(ss_name, &PatchParams::default(), &patch).await
+}
+
+/// Step a rolling Citus upgrade forward by at most one node
+///
+/// Workers are upgraded from the highest ordinal down, then the coordinator, which stays held
+/// back until every worker is done. Once a node's pod is ready on the new image its extension is
+/// updated, and the next node is only released after that has succeeded. Returns the progress
+/// of the upgrade, or `None` when no upgrade is in progress.
+pub async fn reconcile(
+    client: Client,
+    name: &str,
+    master: &StatefulSet,
+    workers: &StatefulSet,
+    spec: &CitusClusterSpec,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<Option<UpgradeStatus>, Error> {
+    let wqname = workers::qname(name);
+    let replicas = workers::replicas(workers);
+    if pending(workers) && replicas > 0 {
+        let ordinal = partition(workers).min(replicas - 1);
+        let node = Node {
+            id: format!("worker-{ordinal}"),
+            pod: format!("{wqname}-{ordinal}"),
+            host: workers::host(name, ordinal, spec, namespace),
+        };
+        match step(client.clone(), name, workers, &node, spec, oref, namespace).await? {
+            Step::Waiting => return Ok(Some(progress(spec, &node, None))),
+            Step::Failed(reason) => return Ok(Some(progress(spec, &node, Some(reason)))),
+            Step::Done if ordinal > 0 => {
+                set_partition(client, &wqname, ordinal - 1, namespace).await?;
+                return Ok(Some(progress(spec, &node, None)));
+            }
+            Step::Done => {
+                finish(client.clone(), &wqname, namespace).await?;
+            }
+        }
+    } else if pending(workers) {
+        finish(client.clone(), &wqname, namespace).await?;
+    }
+
+    if pending(master) {
+        let node = Node {
+            id: "coordinator".to_owned(),
+            pod: format!("{name}-0"),
+            host: master::host(name, spec, namespace),
+        };
+        if partition(master) > 0 {
+            set_partition(client, name, 0, namespace).await?;
+            return Ok(Some(progress(spec, &node, None)));
+        }
+        match step(client.clone(), name, master, &node, spec, oref, namespace).await? {
+            Step::Waiting => return Ok(Some(progress(spec, &node, None))),
+            Step::Failed(reason) => return Ok(Some(progress(spec, &node, Some(reason)))),
+            Step::Done => {
+                finish(client, name, namespace).await?;
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Update the extension on `node` once its pod is ready on the latest revision of `ss`
+async fn step(
+    client: Client,
+    name: &str,
+    ss: &StatefulSet,
+    node: &Node,
+    spec: &CitusClusterSpec,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<Step, Error> {
+    if !pod_updated(client.clone(), ss, &node.pod, namespace).await? {
+        return Ok(Step::Waiting);
+    }
+
+    let job = jobs::update_extension(
+        client.clone(),
+        name,
+        &node.id,
+        node.host.clone(),
+        spec,
+        oref,
+        namespace,
+    )
+    .await?;
+    if jobs::failed(&job) {
+        return Ok(Step::Failed(format!(
+            "Updating the citus extension on {} failed",
+            node.pod
+        )));
+    }
+    if !jobs::succeeded(&job) {
+        return Ok(Step::Waiting);
+    }
+
+    jobs::delete_update_job(client, name, &node.id, namespace).await?;
+    Ok(Step::Done)
+}
+
+/// Whether `pod` is ready and running the latest revision of `ss`
+async fn pod_updated(
+    client: Client,
+    ss: &StatefulSet,
+    pod: &str,
+    namespace: &str,
+) -> Result<bool, Error> {
+    let Some(revision) = ss.status.as_ref().and_then(|s| s.update_revision.as_ref()) else {
+        return Ok(false);
+    };
+    let pod_api: Api<Pod> = Api::namespaced(client, namespace);
+    let Some(pod) = pod_api.get_opt(pod).await? else {
+        return Ok(false);
+    };
+
+    let on_revision = pod
+        .metadata
+        .labels
+        .as_ref()
+        .and_then(|l| l.get("controller-revision-hash"))
+        == Some(revision);
+    let ready = pod
+        .status
+        .as_ref()
+        .and_then(|s| s.conditions.as_ref())
+        .is_some_and(|conditions| {
+            conditions
+                .iter()
+                .any(|c| c.type_ == "Ready" && c.status == "True")
+        });
+    Ok(on_revision && ready)
+}
+
+async fn set_partition(
+    client: Client,
+    ss_name: &str,
+    partition: i32,
+    namespace: &str,
+) -> Result<StatefulSet, Error> {
+    let ss_api: Api<StatefulSet> = Api::namespaced(client, namespace);
+    let partition: Value = json!({
+        "spec": {
+            "updateStrategy": {
+                "rollingUpdate": {
+                    "partition": partition
+                }
+            }
+        }
+    });
+    let patch: Patch<&Value> = Patch::Merge(&partition);
+    ss_api.patch(ss_name, &PatchParams::default(), &patch).await
+}
+
+async fn finish(client: Client, ss_name: &str, namespace: &str) -> Result<StatefulSet, Error> {
+    let ss_api: Api<StatefulSet> = Api::namespaced(client, namespace);
+    let done: Value = json!({
+        "metadata": {
+            "annotations": {
+                PENDING_ANNOTATION: null
+            }
+        }
+    });
+    let patch: Patch<&Value> = Patch::Merge(&done);
+    ss_api.patch(ss_name, &PatchParams::default(), &patch).await
+}
+
+/// Whether a rolling upgrade of the StatefulSet has not finished yet
+pub fn pending(ss: &StatefulSet) -> bool {
+    ss.metadata
+        .annotations
+        .as_ref()
+        .is_some_and(|a| a.contains_key(PENDING_ANNOTATION))
+}
+
+fn partition(ss: &StatefulSet) -> i32 {
+    ss.spec
+        .as_ref()
+        .and_then(|s| s.update_strategy.as_ref())
+        .and_then(|s| s.rolling_update.as_ref())
+        .and_then(|r| r.partition)
+        .unwrap_or_default()
+}
+
+fn progress(spec: &CitusClusterSpec, node: &Node, failure: Option<String>) -> UpgradeStatus {
+    UpgradeStatus {
+        image: spec.image.clone(),
+        node: node.pod.clone(),
+        failure,
+    }
+}
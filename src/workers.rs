@@ -2,34 +2,66 @@ use std::collections::BTreeMap;
 
 use k8s_openapi::api::apps::v1::{StatefulSet, StatefulSetSpec};
 use k8s_openapi::api::core::v1::{
-    Container, ContainerPort, EnvVar, PodSpec, PodTemplateSpec, Service, ServicePort, ServiceSpec,
-    VolumeMount,
+    Container, ContainerPort, PersistentVolumeClaim, PodSpec, PodTemplateSpec, Service,
+    ServicePort, ServiceSpec, VolumeMount,
 };
-use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, ObjectMeta};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, ObjectMeta, OwnerReference};
 use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
 use kube::{Api, Client, Error};
-use kube::api::{DeleteParams, PostParams};
+use kube::api::{DeleteParams, Patch, PatchParams};
+use serde_json::{json, Value};
 
-use crate::storage;
+use crate::{cluster, credentials, postgresql, resources, scheduling, storage, FIELD_MANAGER};
+use crate::crd::CitusClusterSpec;
+
+const REGISTERED_ANNOTATION: &str = "citusclusters.jw3.xyz/registered-workers";
+/// Set on every worker set applied since registration was tracked, so a missing count means
+/// none are registered yet
+const TRACKED_ANNOTATION: &str = "citusclusters.jw3.xyz/registration-tracked";
 
 pub async fn deploy(
     client: Client,
     name: &str,
     cnt: i32,
-    storage: usize,
+    claims: Vec<PersistentVolumeClaim>,
+    spec: &CitusClusterSpec,
+    oref: &OwnerReference,
     namespace: &str,
 ) -> Result<StatefulSet, Error> {
     let mut worker_labels: BTreeMap<String, String> = BTreeMap::new();
     worker_labels.insert("app".to_owned(), name.to_owned());
 
-    let mut worker_node_selector: BTreeMap<String, String> = BTreeMap::new();
-    worker_node_selector.insert("citus-cluster-tag".to_owned(), "worker".to_owned());
+    // Only the pods are told apart from the coordinator, as the selector cannot change
+    let mut worker_pod_labels = worker_labels.clone();
+    worker_pod_labels.insert("node".to_owned(), "worker".to_owned());
+
+    let mut env = vec![credentials::env("POSTGRES_PASSWORD", name, spec)];
+    let mut volume_mounts = vec![
+        VolumeMount {
+            mount_path: storage::DATA_DIR.to_owned(),
+            name: name.to_owned(),
+            ..Default::default()
+        },
+        postgresql::volume_mount(),
+    ];
+    if storage::has_wal(name, &claims) {
+        env.push(storage::wal_env());
+        volume_mounts.push(storage::wal_volume_mount(name));
+    }
+
+    let pod_annotations =
+        postgresql::pod_annotations(client.clone(), name, spec, namespace).await?;
 
     let ss: StatefulSet = StatefulSet {
         metadata: ObjectMeta {
             name: Some(qname(name)),
             namespace: Some(namespace.to_owned()),
             labels: Some(worker_labels.clone()),
+            annotations: Some(BTreeMap::from([(
+                TRACKED_ANNOTATION.to_owned(),
+                "true".to_owned(),
+            )])),
+            owner_references: Some(vec![oref.clone()]),
             ..ObjectMeta::default()
         },
         spec: Some(StatefulSetSpec {
@@ -41,62 +73,154 @@ pub async fn deploy(
             },
             template: PodTemplateSpec {
                 spec: Some(PodSpec {
-                    node_selector: Some(worker_node_selector),
+                    image_pull_secrets: spec.image_pull_secrets.clone(),
+                    volumes: Some(vec![postgresql::volume(name)]),
                     containers: vec![Container {
                         name: "worker".to_owned(),
-                        image: Some("citusdata/citus:12.1".to_owned()),
-                        image_pull_policy: Some("IfNotPresent".to_owned()),
+                        image: Some(spec.image.clone()),
+                        image_pull_policy: Some(spec.image_pull_policy.clone()),
+                        args: Some(postgresql::args(spec, spec.worker.as_ref())),
+                        resources: resources::requirements(spec.worker.as_ref()),
                         ports: Some(vec![ContainerPort {
                             container_port: 5432,
                             ..ContainerPort::default()
                         }]),
-                        env: Some(vec![EnvVar {
-                            name: "POSTGRES_PASSWORD".to_owned(),
-                            value: Some("yourpassword".to_owned()),
-                            ..EnvVar::default()
-                        }]),
-                        volume_mounts: Some(vec![VolumeMount {
-                            mount_path: "/var/lib/postgresql/data".to_owned(),
-                            name: name.to_owned(),
-                            ..Default::default()
-                        }]),
+                        env: Some(env),
+                        volume_mounts: Some(volume_mounts),
                         ..Container::default()
                     }],
-                    ..PodSpec::default()
+                    ..scheduling::pod_spec(
+                        spec.worker.as_ref(),
+                        Some(scheduling::spread(worker_pod_labels.clone())),
+                    )
                 }),
                 metadata: Some(ObjectMeta {
-                    labels: Some(worker_labels.clone()),
+                    labels: Some(worker_pod_labels),
+                    annotations: Some(pod_annotations),
                     ..ObjectMeta::default()
                 }),
             },
-            volume_claim_templates: Some(vec![storage::volume_claim_template(name, storage)]),
+            volume_claim_templates: Some(claims),
+            persistent_volume_claim_retention_policy: Some(storage::retention_policy(spec)),
             ..StatefulSetSpec::default()
         }),
         ..StatefulSet::default()
     };
 
     let ss_api: Api<StatefulSet> = Api::namespaced(client.clone(), namespace);
-    ss_api.create(&PostParams::default(), &ss).await
+    ss_api
+        .patch(
+            &qname(name),
+            &PatchParams::apply(FIELD_MANAGER).force(),
+            &Patch::Apply(&ss),
+        )
+        .await
+}
+
+/// Fetch the live workers StatefulSet, if one exists
+pub async fn get(
+    client: Client,
+    name: &str,
+    namespace: &str,
+) -> Result<Option<StatefulSet>, Error> {
+    let ss_api: Api<StatefulSet> = Api::namespaced(client, namespace);
+    ss_api.get_opt(&qname(name)).await
+}
+
+/// Record how many worker ordinals have been registered with the coordinator
+pub async fn mark_registered(
+    client: Client,
+    name: &str,
+    cnt: i32,
+    namespace: &str,
+) -> Result<StatefulSet, Error> {
+    let ss_api: Api<StatefulSet> = Api::namespaced(client, namespace);
+    let registered: Value = json!({
+        "metadata": {
+            "annotations": {
+                REGISTERED_ANNOTATION: cnt.to_string()
+            }
+        }
+    });
+    let patch: Patch<&Value> = Patch::Merge(&registered);
+    ss_api
+        .patch(&qname(name), &PatchParams::default(), &patch)
+        .await
+}
+
+/// Number of worker ordinals registered with the coordinator
+///
+/// Worker sets created before registration was tracked were registered in full at creation.
+pub fn registered(ss: &StatefulSet) -> i32 {
+    let registered = ss
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(REGISTERED_ANNOTATION))
+        .and_then(|v| v.parse().ok());
+    match registered {
+        Some(registered) => registered,
+        None if tracked(ss) => 0,
+        None => replicas(ss),
+    }
 }
 
-pub async fn expose(client: Client, name: &str, namespace: &str) -> Result<Service, Error> {
-    let mut master_labels: BTreeMap<String, String> = BTreeMap::new();
-    master_labels.insert("app".to_owned(), name.to_owned());
-    master_labels.insert("node".to_owned(), "master".to_owned());
+/// Whether the worker set was applied since registration was tracked
+pub fn tracked(ss: &StatefulSet) -> bool {
+    ss.metadata.annotations.as_ref().is_some_and(|a| {
+        a.contains_key(TRACKED_ANNOTATION) || a.contains_key(REGISTERED_ANNOTATION)
+    })
+}
+
+pub fn ready_replicas(ss: &StatefulSet) -> i32 {
+    ss.status
+        .as_ref()
+        .and_then(|s| s.ready_replicas)
+        .unwrap_or_default()
+}
 
+pub fn replicas(ss: &StatefulSet) -> i32 {
+    ss.spec
+        .as_ref()
+        .and_then(|s| s.replicas)
+        .unwrap_or_default()
+}
+
+/// Apply the headless worker Service that gives every worker pod a stable DNS name
+///
+/// Addresses are published before the pods are ready, so the coordinator can resolve a worker
+/// while it starts up. A Service created before it was headless is replaced, as the cluster IP
+/// of a Service cannot be changed.
+pub async fn expose(
+    client: Client,
+    name: &str,
+    oref: &OwnerReference,
+    namespace: &str,
+) -> Result<Service, Error> {
     let mut worker_labels: BTreeMap<String, String> = BTreeMap::new();
     worker_labels.insert("app".to_owned(), name.to_owned());
 
-    let mut master_selector_labels: BTreeMap<String, String> = BTreeMap::new();
-    master_selector_labels.insert("node".to_owned(), "master".to_owned());
+    let mut worker_selector_labels = worker_labels.clone();
+    worker_selector_labels.insert("node".to_owned(), "worker".to_owned());
 
     let service_api: Api<Service> = Api::namespaced(client.clone(), namespace);
+    let live = service_api.get_opt(&qname(name)).await?;
+    let cluster_ip = live
+        .as_ref()
+        .and_then(|svc| svc.spec.as_ref())
+        .and_then(|spec| spec.cluster_ip.as_deref());
+    if cluster_ip.is_some_and(|ip| ip != "None") {
+        service_api
+            .delete(&qname(name), &DeleteParams::default())
+            .await?;
+    }
 
     let headless_svc = Service {
         metadata: ObjectMeta {
             name: Some(qname(name)),
             namespace: Some(namespace.to_owned()),
             labels: Some(worker_labels.clone()),
+            owner_references: Some(vec![oref.clone()]),
             ..ObjectMeta::default()
         },
         spec: Some(ServiceSpec {
@@ -106,31 +230,104 @@ pub async fn expose(client: Client, name: &str, namespace: &str) -> Result<Servi
                 target_port: Some(IntOrString::Int(5432)),
                 ..ServicePort::default()
             }]),
-            selector: Some(worker_labels),
-            cluster_ip: None,
+            selector: Some(worker_selector_labels),
+            cluster_ip: Some("None".to_owned()),
+            publish_not_ready_addresses: Some(true),
             ..ServiceSpec::default()
         }),
         ..Service::default()
     };
 
     service_api
-        .create(&PostParams::default(), &headless_svc)
+        .patch(
+            &qname(name),
+            &PatchParams::apply(FIELD_MANAGER).force(),
+            &Patch::Apply(&headless_svc),
+        )
         .await
 }
 
-pub async fn delete(client: Client, name: &str, namespace: &str) -> Result<(), Error> {
+/// Delete the workers and their Service, returning those that could not be deleted
+pub async fn delete(client: Client, name: &str, namespace: &str) -> Vec<String> {
     let ss_api: Api<StatefulSet> = Api::namespaced(client.clone(), namespace);
     let service_api: Api<Service> = Api::namespaced(client.clone(), namespace);
 
     let qname = qname(name);
-    let dparams = DeleteParams::default();
-
-    ss_api.delete(&qname, &dparams).await?;
-    service_api.delete(&qname, &dparams).await?;
-
-    Ok(())
+    [
+        cluster::delete_child(&ss_api, &qname).await,
+        cluster::delete_child(&service_api, &qname).await,
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
 }
 
 pub(crate) fn qname(name: &str) -> String {
     format!("{name}-workers")
 }
+
+/// Stable, fully qualified DNS name of the worker pod with ordinal `i`, served by the headless
+/// worker Service
+pub(crate) fn host(name: &str, i: i32, spec: &CitusClusterSpec, namespace: &str) -> String {
+    let wqname = qname(name);
+    format!(
+        "{wqname}-{i}.{wqname}.{namespace}.svc.{}",
+        spec.cluster_domain
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::apps::v1::StatefulSetSpec;
+
+    use super::*;
+
+    fn stateful_set(replicas: i32, annotations: &[(&str, &str)]) -> StatefulSet {
+        StatefulSet {
+            metadata: ObjectMeta {
+                annotations: (!annotations.is_empty()).then(|| {
+                    annotations
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect()
+                }),
+                ..ObjectMeta::default()
+            },
+            spec: Some(StatefulSetSpec {
+                replicas: Some(replicas),
+                ..StatefulSetSpec::default()
+            }),
+            ..StatefulSet::default()
+        }
+    }
+
+    #[test]
+    fn registered_count_is_read_from_the_annotation() {
+        let ss = stateful_set(3, &[(REGISTERED_ANNOTATION, "2")]);
+        assert_eq!(registered(&ss), 2);
+        assert!(tracked(&ss));
+    }
+
+    #[test]
+    fn tracked_sets_without_a_count_have_none_registered() {
+        let ss = stateful_set(3, &[(TRACKED_ANNOTATION, "true")]);
+        assert_eq!(registered(&ss), 0);
+        assert!(tracked(&ss));
+    }
+
+    #[test]
+    fn older_sets_were_registered_in_full() {
+        let ss = stateful_set(3, &[]);
+        assert_eq!(registered(&ss), 3);
+        assert!(!tracked(&ss));
+    }
+
+    #[test]
+    fn unreadable_count_on_a_tracked_set_counts_as_none() {
+        let ss = stateful_set(
+            3,
+            &[(TRACKED_ANNOTATION, "true"), (REGISTERED_ANNOTATION, "x")],
+        );
+        assert_eq!(registered(&ss), 0);
+    }
+}